@@ -1,4 +1,10 @@
-use clap::{Parser, Subcommand};
+use crate::config::{parse_crossfade, RepeatMode, ReplayGainMode};
+use crate::duration;
+use crate::ipc::{Seek, VolumeChange};
+use crate::music_library::{EditPlaylist, EditSong, FieldEdit, SongFilter};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// Music Library Manager CLI
 #[derive(Parser, Debug)]
@@ -7,22 +13,65 @@ use clap::{Parser, Subcommand};
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    /// Keep the library and all other files in this directory, like
+    /// MUSIC_LIB_HOME
+    #[arg(long, global = true, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Play all songs in the queue
-    Play,
+    Play {
+        /// Overlap consecutive songs by this long, e.g. 5s, or off
+        #[arg(long, value_parser = parse_crossfade)]
+        crossfade: Option<Duration>,
+    },
     /// Pause playback
     Pause,
     /// Resume playback
     Resume,
     /// Skip current song
     Skip,
+    /// Restart the current song, or go back to the previous one near its start
+    Previous,
     /// Stop playback
     Stop,
     /// View playback status
     Status,
+    /// Seek within the current song
+    Seek {
+        /// Position such as 1:00 or 90s, or an offset such as +30s or -10s
+        #[arg(value_parser = parse_seek, allow_hyphen_values = true)]
+        position: Seek,
+    },
+    /// View or change the volume
+    Volume {
+        /// Level from 0 to 100, or an adjustment such as +10 or -10
+        #[arg(value_parser = parse_volume, allow_hyphen_values = true)]
+        level: Option<VolumeChange>,
+    },
+    /// View or change the repeat mode
+    Repeat { mode: Option<RepeatMode> },
+    /// View or change shuffle mode
+    Shuffle {
+        mode: Option<Toggle>,
+        /// Seed for a repeatable shuffle order
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// View or change how long consecutive songs overlap
+    Crossfade {
+        /// Length such as 5s, or off for gapless playback
+        #[arg(value_parser = parse_crossfade)]
+        length: Option<Duration>,
+    },
+    /// View or change which ReplayGain values are applied during playback
+    Replaygain { mode: Option<ReplayGainMode> },
+    /// Mute playback
+    Mute,
+    /// Unmute playback
+    Unmute,
     /// Add, Edit, Remove, or List songs
     Songs {
         #[clap(subcommand)]
@@ -33,15 +82,97 @@ pub enum Commands {
         #[clap(subcommand)]
         action: Option<PlaylistAction>,
     },
+    /// List or View albums
+    Albums {
+        #[clap(subcommand)]
+        action: Option<AlbumAction>,
+    },
+    /// List or View artists
+    Artists {
+        #[clap(subcommand)]
+        action: Option<ArtistAction>,
+    },
     /// Manage queue
     Queue {
         #[clap(subcommand)]
         action: Option<QueueAction>,
     },
+    /// Check the library for missing or unplayable files
+    Library {
+        #[clap(subcommand)]
+        action: LibraryAction,
+    },
+    /// Measure song loudness
+    Loudness {
+        #[clap(subcommand)]
+        action: LoudnessAction,
+    },
     /// Scan a directory for music files
     Scan {
-        /// Directory path
-        directory: String,
+        /// Directory path, or every configured library root when omitted
+        directory: Option<String>,
+        #[command(flatten)]
+        options: ScanArgs,
+    },
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ScanArgs {
+    /// Skip paths matching this glob, e.g. '@eaDir' or 'samples/**'
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    /// Follow symbolic links
+    #[arg(long)]
+    pub follow_symlinks: bool,
+    /// How deep to look, where 1 means only files directly in the directory
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum LibraryAction {
+    /// List songs whose files are missing, unreadable or undecodable
+    Check,
+    /// Remove missing and undecodable songs from the library, playlists and
+    /// queue
+    Prune {
+        /// Only list what would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Also remove songs whose files can't be read or whose directory is gone
+        #[arg(long)]
+        unreadable: bool,
+    },
+    /// Rescan every configured library root
+    Rescan {
+        #[command(flatten)]
+        options: ScanArgs,
+    },
+    /// Keep the library in sync with a directory as files change
+    Watch {
+        /// Directory path, or every configured library root when omitted
+        directory: Option<String>,
+        /// Wait until nothing has changed for this long before rescanning
+        #[arg(long, default_value = "2s", value_parser = duration::parse)]
+        delay: Duration,
+        #[command(flatten)]
+        options: ScanArgs,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum LoudnessAction {
+    /// Compute ReplayGain values for songs that lack them
+    Analyze {
+        /// Analyze every song, replacing existing values
+        #[arg(long)]
+        force: bool,
     },
 }
 
@@ -53,33 +184,60 @@ pub enum SongAction {
     },
     /// View song details
     View {
+        /// Song Name or ID
         name: String,
     },
     /// Remove a song
     Remove {
+        /// Song Name or ID
         name: String,
     },
-    /// Edit a song
+    /// Edit a song, or every song matching --where
     Edit {
-        /// Song Name
-        name: String,
+        /// Song Name or ID
+        #[arg(required_unless_present = "filters")]
+        name: Option<String>,
         #[clap(subcommand)]
-        /// Name, Artist, Album, or Path
-        field: EditSong,
-        /// New Value
-        value: String,
+        /// Field to change
+        field: Option<EditSong>,
+        /// New Value, or an empty string to clear an optional field
+        #[arg(required_unless_present = "filters")]
+        value: Option<String>,
+        /// Edit every song whose field equals a value, e.g. album="Foo", or
+        /// contains some text, e.g. artist~=foo. Repeat to narrow down.
+        #[arg(
+            long = "where",
+            value_name = "FIELD=VALUE",
+            value_parser = parse_filter,
+            conflicts_with_all = ["name", "value"],
+            requires = "edits"
+        )]
+        filters: Vec<SongFilter>,
+        /// Change a field of the matching songs, e.g. year=1999. Repeat to
+        /// change several.
+        #[arg(
+            long = "set",
+            value_name = "FIELD=VALUE",
+            value_parser = parse_field_edit,
+            requires = "filters"
+        )]
+        edits: Vec<FieldEdit>,
+        /// Apply edits to matching songs without asking
+        #[arg(long, short)]
+        yes: bool,
+        /// Also write the change to the file's tags
+        #[arg(long, overrides_with = "no_write_tags")]
+        write_tags: bool,
+        /// Only change the library, even if tag writing is configured
+        #[arg(long)]
+        no_write_tags: bool,
+        /// Only show what would change
+        #[arg(long)]
+        dry_run: bool,
     },
     List,
 }
 
-#[derive(Debug, Clone, Subcommand)]
-pub enum EditSong {
-    Name,
-    Artist,
-    Album,
-    Path,
-}
-
 #[derive(Subcommand, Debug, Clone)]
 pub enum PlaylistAction {
     /// Create a playlist
@@ -118,15 +276,156 @@ pub enum PlaylistAction {
 }
 
 #[derive(Debug, Clone, Subcommand)]
-pub enum EditPlaylist {
-    Name,
+pub enum AlbumAction {
+    List,
+    /// View an album's tracks
+    View {
+        album_name: String,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ArtistAction {
+    List,
+    /// View an artist's albums and songs
+    View {
+        artist_name: String,
+    },
 }
 
 #[derive(Debug, Clone, Subcommand)]
 pub enum QueueAction {
-    AddSong { song_name: String },
-    AddPlaylist { playlist_name: String },
-    Remove { index: usize },
+    AddSong {
+        song_name: String,
+    },
+    AddPlaylist {
+        playlist_name: String,
+    },
+    /// Add an album in track order
+    AddAlbum {
+        album_name: String,
+    },
+    /// Add all of an artist's songs, album by album
+    AddArtist {
+        artist_name: String,
+    },
+    Remove {
+        index: usize,
+    },
     List,
     Clear,
+    /// Randomly reorder the queue
+    Shuffle {
+        /// Seed for a repeatable order
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+}
+
+fn parse_field(name: &str) -> Result<EditSong, String> {
+    EditSong::from_str(name.trim(), true).map_err(|_| format!("Unknown field '{name}'"))
+}
+
+/// Strips the quotes from a value such as `"Foo"`.
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+fn parse_filter(value: &str) -> Result<SongFilter, String> {
+    let invalid =
+        || format!("Invalid filter '{value}', expected e.g. album=\"Foo\" or artist~=foo");
+    let (field, text) = value.split_once('=').ok_or_else(invalid)?;
+    let (field, contains) = match field.strip_suffix('~') {
+        Some(field) => (field, true),
+        None => (field, false),
+    };
+    Ok(SongFilter {
+        field: parse_field(field)?,
+        value: unquote(text),
+        contains,
+    })
+}
+
+fn parse_field_edit(value: &str) -> Result<FieldEdit, String> {
+    let (field, text) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid edit '{value}', expected e.g. artist=\"Bar\""))?;
+    match parse_field(field)? {
+        EditSong::Path => Err("Paths can only be edited one song at a time".to_string()),
+        field => FieldEdit::new(field, unquote(text)),
+    }
+}
+
+fn parse_seek(value: &str) -> Result<Seek, String> {
+    if let Some(offset) = value.strip_prefix('+') {
+        Ok(Seek::Forward(duration::parse(offset)?))
+    } else if let Some(offset) = value.strip_prefix('-') {
+        Ok(Seek::Back(duration::parse(offset)?))
+    } else {
+        Ok(Seek::To(duration::parse(value)?))
+    }
+}
+
+fn parse_volume(value: &str) -> Result<VolumeChange, String> {
+    let invalid = || format!("Invalid volume '{value}', expected 0-100, +N or -N");
+    let parse = |level: &str| {
+        let level = level.strip_suffix('%').unwrap_or(level);
+        level
+            .parse::<u8>()
+            .ok()
+            .filter(|level| *level <= 100)
+            .ok_or_else(invalid)
+    };
+
+    if let Some(level) = value.strip_prefix('+') {
+        Ok(VolumeChange::Up(parse(level)?))
+    } else if let Some(level) = value.strip_prefix('-') {
+        Ok(VolumeChange::Down(parse(level)?))
+    } else {
+        Ok(VolumeChange::Set(parse(value)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filters() {
+        let filter = parse_filter("album=\"Greatest Hits\"").unwrap();
+        assert_eq!(filter.field, EditSong::Album);
+        assert_eq!(filter.value, "Greatest Hits");
+        assert!(!filter.contains);
+
+        let filter = parse_filter(" Album-Artist ~= beat ").unwrap();
+        assert_eq!(filter.field, EditSong::AlbumArtist);
+        assert_eq!(filter.value, "beat");
+        assert!(filter.contains);
+
+        // only the first `=` separates the field from the value
+        assert_eq!(parse_filter("name=a=b").unwrap().value, "a=b");
+        assert!(parse_filter("album").is_err());
+        assert!(parse_filter("colour=red").is_err());
+    }
+
+    #[test]
+    fn parses_field_edits() {
+        let edit = parse_field_edit("artist=\"The Band\"").unwrap();
+        assert_eq!(edit.field, EditSong::Artist);
+        assert_eq!(edit.value, "The Band");
+
+        // an empty value clears the field
+        assert_eq!(parse_field_edit("year=").unwrap().value, "");
+        assert_eq!(parse_field_edit("track=\"7\"").unwrap().value, "7");
+
+        assert!(parse_field_edit("path=/music/a.mp3").is_err());
+        assert!(parse_field_edit("year=last").is_err());
+        assert!(parse_field_edit("track=-1").is_err());
+        assert!(parse_field_edit("genre").is_err());
+    }
 }
@@ -1,19 +1,283 @@
+use crate::duration;
+use clap::ValueEnum;
 use home::home_dir;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 
-const DATA_DIR: &str = ".local/share/music-lib-manager/";
+/// User preferences read from `config.toml`. Every section and field is
+/// optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub library: LibraryConfig,
+    pub output: OutputConfig,
+    pub player: PlayerConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// Directories refreshed by a bare `scan`, `library rescan` and
+    /// `library watch`
+    pub roots: Vec<String>,
+    /// Globs skipped in every scan, on top of those given with `--exclude`
+    pub exclude: Vec<String>,
+    pub follow_symlinks: bool,
+    pub max_depth: Option<usize>,
+    /// Write song edits to file tags unless `--no-write-tags` is given
+    pub write_tags: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Name of the audio device to play on instead of the system default
+    pub device: Option<String>,
+}
+
+/// Player settings to start with, until they are changed with the player
+/// commands.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerConfig {
+    pub volume: Option<u8>,
+    pub repeat: Option<RepeatMode>,
+    pub shuffle: Option<bool>,
+    #[serde(deserialize_with = "deserialize_crossfade")]
+    pub crossfade: Option<Duration>,
+    pub replaygain: Option<ReplayGainMode>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    /// Stop once the queue is empty
+    #[default]
+    Off,
+    /// Replay the current song
+    One,
+    /// Requeue songs once they finish
+    All,
+}
+
+impl fmt::Display for RepeatMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepeatMode::Off => write!(f, "off"),
+            RepeatMode::One => write!(f, "one"),
+            RepeatMode::All => write!(f, "all"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayGainMode {
+    /// Play songs at their original level
+    #[default]
+    Off,
+    /// Normalize each song on its own
+    Track,
+    /// Normalize whole albums, keeping the level differences between songs
+    Album,
+}
+
+impl fmt::Display for ReplayGainMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayGainMode::Off => write!(f, "off"),
+            ReplayGainMode::Track => write!(f, "track"),
+            ReplayGainMode::Album => write!(f, "album"),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file, or returns the defaults when there is none.
+    pub fn load() -> io::Result<Self> {
+        let path = get_config_file_path();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e),
+        };
+        let mut config: Config = toml::from_str(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        })?;
+        for root in &mut config.library.roots {
+            *root = expand_home(root);
+        }
+        Ok(config)
+    }
+}
+
+/// Accepts the same lengths as the `crossfade` command, e.g. "5s" or "off".
+fn deserialize_crossfade<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_crossfade(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// A crossfade length such as "5s", or "off".
+pub fn parse_crossfade(value: &str) -> Result<Duration, String> {
+    match value {
+        "off" => Ok(Duration::ZERO),
+        _ => duration::parse(value),
+    }
+}
+
+/// Expands a leading `~/` to the home directory.
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().into_owned(),
+        _ => path.to_string(),
+    }
+}
+
+/// Directory name used under each XDG base directory
+const APP_DIR: &str = "music-lib-manager";
+
+/// Keeps every file in this one directory instead of the XDG locations, so
+/// independent libraries can coexist. Also set by `--data-dir`.
+pub const HOME_VAR: &str = "MUSIC_LIB_HOME";
+
+/// Resolves a base directory: the `MUSIC_LIB_HOME` override, then `var` if
+/// it holds an absolute path as the XDG spec requires, then `fallback`
+/// under the home directory.
+fn base_dir(var: &str, fallback: &str) -> PathBuf {
+    if let Some(dir) = home_override() {
+        return dir;
+    }
+    match env::var_os(var).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir.join(APP_DIR),
+        _ => {
+            let home = home_dir().expect("Unable to find home directory");
+            home.join(fallback).join(APP_DIR)
+        }
+    }
+}
+
+fn home_override() -> Option<PathBuf> {
+    env::var_os(HOME_VAR)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The library, queue and player settings
+pub fn get_data_dir() -> PathBuf {
+    base_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// Player state and daemon logs
+pub fn get_state_dir() -> PathBuf {
+    base_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// The lock, control socket and daemon pid file. Falls back to the state
+/// directory when there is no runtime directory.
+pub fn get_runtime_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() && home_override().is_none() => dir.join(APP_DIR),
+        _ => get_state_dir(),
+    }
+}
+
+pub fn get_config_file_path() -> PathBuf {
+    base_dir("XDG_CONFIG_HOME", ".config").join("config.toml")
+}
 
 pub fn get_queue_file_path() -> PathBuf {
-    let home = home_dir().expect("Unable to find home directory");
-    home.join(DATA_DIR).join("queue.json")
+    get_data_dir().join("queue.json")
 }
 
 pub fn get_library_file_path() -> PathBuf {
-    let home = home_dir().expect("Unable to find home directory");
-    home.join(DATA_DIR).join("music_library.json")
+    get_data_dir().join("music_library.json")
+}
+
+pub fn get_database_file_path() -> PathBuf {
+    get_data_dir().join("music_library.db")
+}
+
+pub fn get_settings_file_path() -> PathBuf {
+    get_data_dir().join("player_settings.json")
 }
 
 pub fn get_player_file_path() -> PathBuf {
-    let home = home_dir().expect("Unable to find home directory");
-    home.join(DATA_DIR).join("player_state.json")
+    get_state_dir().join("player_state.json")
+}
+
+pub fn get_log_file_path() -> PathBuf {
+    get_state_dir().join("player.log")
+}
+
+pub fn get_error_log_file_path() -> PathBuf {
+    get_state_dir().join("player.err")
+}
+
+pub fn get_lock_file_path() -> PathBuf {
+    get_runtime_dir().join("state.lock")
+}
+
+pub fn get_socket_path() -> PathBuf {
+    get_runtime_dir().join("player.sock")
+}
+
+pub fn get_pid_file_path() -> PathBuf {
+    get_runtime_dir().join("player.pid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the only test touching these variables, so it can't race another
+    #[test]
+    fn resolves_directories_in_order() {
+        env::set_var("HOME", "/home/user");
+        env::remove_var(HOME_VAR);
+        env::remove_var("XDG_DATA_HOME");
+        env::remove_var("XDG_RUNTIME_DIR");
+        let local = PathBuf::from("/home/user/.local");
+        assert_eq!(get_data_dir(), local.join("share").join(APP_DIR));
+        assert_eq!(get_runtime_dir(), local.join("state").join(APP_DIR));
+
+        // relative values are ignored as the XDG spec requires
+        env::set_var("XDG_DATA_HOME", "data");
+        env::set_var("XDG_RUNTIME_DIR", "run");
+        assert_eq!(get_data_dir(), local.join("share").join(APP_DIR));
+        assert_eq!(get_runtime_dir(), local.join("state").join(APP_DIR));
+
+        env::set_var("XDG_DATA_HOME", "/data");
+        env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
+        assert_eq!(get_data_dir(), PathBuf::from("/data").join(APP_DIR));
+        assert_eq!(
+            get_runtime_dir(),
+            PathBuf::from("/run/user/1000").join(APP_DIR)
+        );
+
+        // the override wins over everything, the runtime directory included
+        env::set_var(HOME_VAR, "/library");
+        assert_eq!(get_data_dir(), PathBuf::from("/library"));
+        assert_eq!(get_runtime_dir(), PathBuf::from("/library"));
+        assert_eq!(
+            get_config_file_path(),
+            PathBuf::from("/library/config.toml")
+        );
+
+        // an empty override counts as unset
+        env::set_var(HOME_VAR, "");
+        assert_eq!(get_data_dir(), PathBuf::from("/data").join(APP_DIR));
+    }
 }
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+/// Parses `90`, `90s`, `2m`, `1:30` or `1:02:03` into a duration.
+pub fn parse(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{value}', expected e.g. 90, 30s, 2m or 1:30");
+
+    let seconds = if let Some(minutes) = value.strip_suffix('m') {
+        minutes.parse::<f64>().map_err(|_| invalid())? * 60.0
+    } else if value.contains(':') {
+        // [hours:]minutes:seconds
+        let mut seconds = 0.0;
+        for part in value.split(':') {
+            let part = part.parse::<f64>().map_err(|_| invalid())?;
+            if part.is_sign_negative() {
+                return Err(invalid());
+            }
+            seconds = seconds * 60.0 + part;
+        }
+        seconds
+    } else {
+        let seconds = value.strip_suffix('s').unwrap_or(value);
+        seconds.parse::<f64>().map_err(|_| invalid())?
+    };
+
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
+
+/// Formats a duration as `m:ss`, or `h:mm:ss` past an hour.
+pub fn format(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        let secs = Duration::from_secs;
+        assert_eq!(parse("90"), Ok(secs(90)));
+        assert_eq!(parse("30s"), Ok(secs(30)));
+        assert_eq!(parse("2.5s"), Ok(Duration::from_millis(2500)));
+        assert_eq!(parse("2m"), Ok(secs(120)));
+        assert_eq!(parse("1:30"), Ok(secs(90)));
+        assert_eq!(parse("1:02:03"), Ok(secs(3723)));
+        assert_eq!(parse("0"), Ok(Duration::ZERO));
+
+        for invalid in ["", "s", "abc", "-5", "-2m", "1:-30", "1::30", "5h", "inf"] {
+            assert!(parse(invalid).is_err(), "{invalid} should be rejected");
+        }
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format(Duration::from_secs(5)), "0:05");
+        assert_eq!(format(Duration::from_secs(754)), "12:34");
+        assert_eq!(format(Duration::from_secs(3723)), "1:02:03");
+    }
+}
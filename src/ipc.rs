@@ -0,0 +1,75 @@
+use crate::config::{self, RepeatMode, ReplayGainMode};
+use crate::player::Status;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Commands accepted by the player daemon, sent as one JSON object per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    Play,
+    Pause,
+    Resume,
+    Skip,
+    Previous,
+    Stop,
+    Status,
+    Seek(Seek),
+    Volume(VolumeChange),
+    Mute,
+    Unmute,
+    Repeat(RepeatMode),
+    Shuffle { enabled: bool, seed: Option<u64> },
+    Crossfade(Duration),
+    ReplayGain(ReplayGainMode),
+}
+
+/// A change to the volume, in percent.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum VolumeChange {
+    Set(u8),
+    Up(u8),
+    Down(u8),
+}
+
+/// A position to seek to in the current song.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Seek {
+    To(Duration),
+    Forward(Duration),
+    Back(Duration),
+}
+
+/// The daemon's reply to a single `Request`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Ok(String),
+    Status(Box<Status>),
+    Error(String),
+}
+
+/// Sends a request to the running daemon and waits for its reply. Fails if
+/// no daemon is listening.
+pub fn send(request: &Request) -> io::Result<Response> {
+    let stream = UnixStream::connect(config::get_socket_path())?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    write_message(&stream, request)?;
+    read_message(&mut BufReader::new(&stream))
+}
+
+pub fn write_message<T: Serialize>(mut stream: &UnixStream, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)
+}
+
+pub fn read_message<T: DeserializeOwned>(reader: &mut BufReader<&UnixStream>) -> io::Result<T> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(serde_json::from_str(&line)?)
+}
@@ -0,0 +1,36 @@
+use crate::config;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+
+/// Exclusive advisory lock serializing queue and player state updates between
+/// CLI invocations and the player daemon. Released when dropped.
+///
+/// Locks belong to the open file, so a forked child inherits it; drop the lock
+/// before starting the daemon.
+pub struct StateLock {
+    file: File,
+}
+
+impl StateLock {
+    /// Blocks until the lock is available.
+    pub fn acquire() -> io::Result<Self> {
+        let file_name = config::get_lock_file_path();
+        if let Some(parent) = file_name.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(file_name)?;
+        file.lock()?;
+        Ok(StateLock { file })
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
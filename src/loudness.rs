@@ -0,0 +1,219 @@
+use rodio::Source;
+use std::f64::consts::PI;
+
+/// Loudness that ReplayGain 2.0 normalises to, in LUFS
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Blocks quieter than this are ignored, in LUFS
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks this far below the ungated loudness are ignored, in LU
+const RELATIVE_GATE: f64 = 10.0;
+
+/// Gating blocks are 400ms long and start every 100ms
+const STEPS_PER_BLOCK: usize = 4;
+const STEPS_PER_SECOND: u32 = 10;
+
+/// EBU R128 measurement of a recording: the mean square energy of each of
+/// its gating blocks, and its sample peak. Measurements of several tracks can
+/// be combined to measure a whole album.
+#[derive(Debug, Clone, Default)]
+pub struct Measurement {
+    blocks: Vec<f64>,
+    peak: f64,
+}
+
+impl Measurement {
+    pub fn extend(&mut self, other: &Measurement) {
+        self.blocks.extend_from_slice(&other.blocks);
+        self.peak = self.peak.max(other.peak);
+    }
+
+    /// The highest sample level as a fraction of full scale.
+    pub fn peak(&self) -> f32 {
+        self.peak as f32
+    }
+
+    /// Gated integrated loudness in LUFS, or `None` for silence.
+    pub fn loudness(&self) -> Option<f64> {
+        let audible: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|energy| to_lufs(*energy) > ABSOLUTE_GATE)
+            .collect();
+        let threshold = to_lufs(mean(&audible)?) - RELATIVE_GATE;
+        let gated: Vec<f64> = audible
+            .into_iter()
+            .filter(|energy| to_lufs(*energy) > threshold)
+            .collect();
+        mean(&gated).map(to_lufs)
+    }
+
+    /// ReplayGain adjustment in dB that brings this to the reference level.
+    pub fn gain(&self) -> Option<f32> {
+        self.loudness()
+            .map(|loudness| (REFERENCE_LOUDNESS - loudness) as f32)
+    }
+}
+
+/// Decodes a whole source, measuring its loudness.
+pub fn measure<S>(source: S) -> Measurement
+where
+    S: Source<Item = i16>,
+{
+    let channels = usize::from(source.channels().max(1));
+    let rate = source.sample_rate();
+    let step_frames = (rate / STEPS_PER_SECOND).max(1) as usize;
+
+    let mut filters: Vec<KWeighting> = (0..channels).map(|_| KWeighting::new(rate)).collect();
+    let mut steps = Vec::new();
+    let mut sum = 0.0;
+    let mut samples = 0;
+    let mut peak: f64 = 0.0;
+
+    for (i, sample) in source.enumerate() {
+        let sample = f64::from(sample) / f64::from(i16::MAX);
+        peak = peak.max(sample.abs());
+        let value = filters[i % channels].process(sample);
+        sum += value * value;
+        samples += 1;
+        if samples == step_frames * channels {
+            // every channel is weighted equally, as for mono and stereo
+            steps.push(sum / step_frames as f64);
+            sum = 0.0;
+            samples = 0;
+        }
+    }
+
+    let blocks = steps
+        .windows(STEPS_PER_BLOCK)
+        .map(|window| window.iter().sum::<f64>() / STEPS_PER_BLOCK as f64)
+        .collect();
+    Measurement { blocks, peak }
+}
+
+fn to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+/// The two-stage K-weighting filter from ITU-R BS.1770: a high shelf
+/// modelling the head followed by a high-pass filter.
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(rate: u32) -> Self {
+        let rate = f64::from(rate);
+
+        let k = (PI * 1681.974450955533 / rate).tan();
+        let q = 0.7071752369554196;
+        let vh = 10f64.powf(3.999843853973347 / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let k = (PI * 38.13547087602444 / rate).tan();
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        KWeighting { shelf, high_pass }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    /// A 997 Hz sine peaking at `dbfs` on every channel.
+    fn sine(dbfs: f64, channels: u16, rate: u32, seconds: u32) -> SamplesBuffer<i16> {
+        let amplitude = 10f64.powf(dbfs / 20.0) * f64::from(i16::MAX);
+        let samples = (0..rate * seconds)
+            .flat_map(|i| {
+                let value = amplitude * (2.0 * PI * 997.0 * f64::from(i) / f64::from(rate)).sin();
+                std::iter::repeat_n(value.round() as i16, usize::from(channels))
+            })
+            .collect::<Vec<i16>>();
+        SamplesBuffer::new(channels, rate, samples)
+    }
+
+    fn assert_near(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("the signal should be audible");
+        assert!(
+            (actual - expected).abs() < 0.1,
+            "measured {actual:.2} LUFS, expected {expected:.2}"
+        );
+    }
+
+    #[test]
+    fn measures_sine_loudness() {
+        // BS.1770 reference: a 0 dBFS 997 Hz sine on one channel is -3.01 LUFS
+        assert_near(measure(sine(-20.0, 1, 48000, 5)).loudness(), -23.01);
+        assert_near(measure(sine(-20.0, 1, 44100, 5)).loudness(), -23.01);
+        // the same signal on both channels is twice the energy
+        assert_near(measure(sine(-20.0, 2, 48000, 5)).loudness(), -20.0);
+
+        let gain = measure(sine(-20.0, 1, 48000, 5)).gain().unwrap();
+        assert!((f64::from(gain) - (REFERENCE_LOUDNESS + 23.01)).abs() < 0.1);
+    }
+
+    #[test]
+    fn gates_silence_and_quiet_passages() {
+        assert_eq!(measure(sine(-100.0, 1, 48000, 2)).loudness(), None);
+
+        // a quiet track barely moves an album dominated by a loud one
+        let mut album = measure(sine(-20.0, 1, 48000, 5));
+        album.extend(&measure(sine(-50.0, 1, 48000, 5)));
+        assert_near(album.loudness(), -23.01);
+        assert!((album.peak() - 0.1).abs() < 0.001);
+    }
+}
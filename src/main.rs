@@ -1,80 +1,193 @@
 mod cli;
 mod config;
+mod duration;
+mod ipc;
+mod lock;
+mod loudness;
 mod music_library;
+mod persist;
 mod player;
+mod progress;
 mod queue;
+mod storage;
+mod tags;
+mod watch;
 
 use clap::Parser;
-use music_library::MusicLibrary;
+use config::Config;
+use fastrand::Rng;
+use music_library::{MusicLibrary, ScanOptions, Song};
 use player::Player;
 use queue::Queue;
+use std::io::ErrorKind;
+use std::{env, path, process};
 
 fn main() {
+    let cli = cli::Cli::parse();
+
+    if let Some(dir) = &cli.data_dir {
+        // read by every path in `config`, and inherited by the player daemon
+        match path::absolute(dir) {
+            Ok(dir) => env::set_var(config::HOME_VAR, dir),
+            Err(e) => {
+                eprintln!("Invalid data directory {}: {e}", dir.display());
+                process::exit(1);
+            }
+        }
+    }
+
+    // only start empty when nothing has been saved yet; anything else
+    // would overwrite the existing data on save
     let mut lib = match MusicLibrary::load() {
         Ok(lib) => lib,
-        Err(_) => MusicLibrary::new(),
-    };
-
-    let mut queue = match Queue::load() {
-        Ok(queue) => queue,
-        Err(_) => Queue::new(),
+        Err(e) if e.kind() == ErrorKind::NotFound => MusicLibrary::new(),
+        Err(e) => {
+            eprintln!("Error loading music library: {e}");
+            process::exit(1);
+        }
     };
 
-    let player = Player::load();
-    let cli = cli::Cli::parse();
-
     match cli.command {
-        cli::Commands::Play => {
-            player.play();
+        cli::Commands::Play { crossfade } => {
+            if let Some(length) = crossfade {
+                Player::load().crossfade(length);
+            }
+            Player::load().play();
         }
         cli::Commands::Pause => {
-            player.pause();
+            Player::load().pause();
         }
         cli::Commands::Resume => {
-            player.resume();
+            Player::load().resume();
         }
         cli::Commands::Skip => {
-            player.skip();
+            Player::load().skip();
+        }
+        cli::Commands::Previous => {
+            Player::load().previous();
         }
         cli::Commands::Stop => {
-            player.stop();
+            Player::load().stop();
         }
         cli::Commands::Status => {
-            player.status();
+            Player::load().status();
+        }
+        cli::Commands::Seek { position } => {
+            Player::load().seek(position);
+        }
+        cli::Commands::Volume { level } => match level {
+            Some(change) => Player::load().volume(change),
+            None => Player::load().status(),
+        },
+        cli::Commands::Repeat { mode } => match mode {
+            Some(mode) => Player::load().repeat(mode),
+            None => Player::load().status(),
+        },
+        cli::Commands::Crossfade { length } => match length {
+            Some(length) => Player::load().crossfade(length),
+            None => Player::load().status(),
+        },
+        cli::Commands::Replaygain { mode } => match mode {
+            Some(mode) => Player::load().replaygain(mode),
+            None => Player::load().status(),
+        },
+        cli::Commands::Shuffle { mode, seed } => match mode {
+            Some(mode) => Player::load().shuffle(mode == cli::Toggle::On, seed),
+            None => Player::load().status(),
+        },
+        cli::Commands::Mute => {
+            Player::load().mute(true);
+        }
+        cli::Commands::Unmute => {
+            Player::load().mute(false);
         }
         cli::Commands::Queue { action } => match action {
-            None => queue.list(),
+            None => load_queue().list(&lib),
             Some(command) => match command {
                 cli::QueueAction::AddSong { song_name } => {
                     if let Some(song) = lib.get_song(song_name.clone()) {
-                        queue.add_song(song.clone());
+                        update_queue(|queue| queue.add_song(song));
                     } else {
                         println!("Song '{song_name}' not found in library.");
                     }
                 }
                 cli::QueueAction::AddPlaylist { playlist_name } => {
                     if let Some(playlist) = lib.get_playlist(playlist_name.clone()) {
-                        queue.add_playlist(playlist.clone());
+                        update_queue(|queue| queue.add_playlist(playlist));
                     } else {
                         println!("Playlist '{playlist_name}' not found in library.");
                     }
                 }
-                cli::QueueAction::Remove { index } => {
+                cli::QueueAction::AddAlbum { album_name } => {
+                    if let Some(album) = lib.get_album(album_name.clone()) {
+                        update_queue(|queue| queue.add_songs(&album.songs));
+                    } else {
+                        println!("Album '{album_name}' not found in library.");
+                    }
+                }
+                cli::QueueAction::AddArtist { artist_name } => {
+                    if let Some(artist) = lib.get_artist(artist_name.clone()) {
+                        update_queue(|queue| queue.add_songs(&artist.songs));
+                    } else {
+                        println!("Artist '{artist_name}' not found in library.");
+                    }
+                }
+                cli::QueueAction::Remove { index } => update_queue(|queue| {
                     if let Err(e) = queue.remove_song(index) {
                         println!("{e}");
                     }
-                }
+                }),
                 cli::QueueAction::List => {
-                    queue.list();
+                    load_queue().list(&lib);
                 }
                 cli::QueueAction::Clear => {
-                    queue.clear();
+                    update_queue(|queue| queue.clear());
+                }
+                cli::QueueAction::Shuffle { seed } => {
+                    let mut rng = match seed {
+                        Some(seed) => Rng::with_seed(seed),
+                        None => Rng::new(),
+                    };
+                    update_queue(|queue| queue.shuffle(&mut rng));
+                    load_queue().list(&lib);
                 }
             },
         },
-        cli::Commands::Scan { directory } => {
-            lib.scan_directory(&directory);
-        }
+        cli::Commands::Library { action } => match action {
+            cli::LibraryAction::Check => lib.check(),
+            cli::LibraryAction::Prune {
+                dry_run,
+                unreadable,
+            } => {
+                let removed = lib.prune(dry_run, unreadable);
+                dequeue(&removed);
+            }
+            cli::LibraryAction::Rescan { options } => scan(&mut lib, None, options),
+            cli::LibraryAction::Watch {
+                directory,
+                delay,
+                options,
+            } => {
+                let config = load_config();
+                let roots = scan_roots(directory, &config);
+                let options = scan_options(options, &config);
+                lib.scan_directories(&roots, &options);
+                if let Err(e) = lib.save() {
+                    println!("Error while saving data: {e}");
+                }
+                // the watcher saves its own changes, so the library loaded
+                // here is stale and must not be saved again
+                if let Err(e) = watch::watch(&roots, &options, delay) {
+                    eprintln!("Error watching for changes: {e}");
+                    process::exit(1);
+                }
+                return;
+            }
+        },
+        cli::Commands::Loudness { action } => match action {
+            cli::LoudnessAction::Analyze { force } => lib.analyze_loudness(force),
+        },
+        cli::Commands::Scan { directory, options } => scan(&mut lib, directory, options),
         cli::Commands::Songs { action } => match action {
             None => lib.list_songs(),
             Some(command) => match command {
@@ -84,17 +197,54 @@ fn main() {
                 cli::SongAction::View { name } => {
                     lib.view_song(name);
                 }
-                cli::SongAction::Edit { name, field, value } => {
-                    lib.edit_song(name, field, value);
+                cli::SongAction::Edit {
+                    name,
+                    field,
+                    value,
+                    filters,
+                    edits,
+                    yes,
+                    write_tags,
+                    no_write_tags,
+                    dry_run,
+                } => {
+                    let write_tags =
+                        !no_write_tags && (write_tags || load_config().library.write_tags);
+                    match (name, field, value) {
+                        (None, None, None) => {
+                            lib.edit_songs(&filters, &edits, write_tags, dry_run, yes)
+                        }
+                        (Some(name), Some(field), Some(value)) => {
+                            lib.edit_song(name, field, value, write_tags, dry_run)
+                        }
+                        (_, Some(_), _) if !filters.is_empty() => {
+                            eprintln!("Use --set to choose fields to change with --where");
+                            process::exit(2);
+                        }
+                        _ => {
+                            eprintln!("Give a song, a value and a field to change");
+                            process::exit(2);
+                        }
+                    }
                 }
                 cli::SongAction::Remove { name } => {
-                    lib.remove_song(name);
+                    if let Some(song) = lib.remove_song(name) {
+                        dequeue(&[song]);
+                    }
                 }
                 cli::SongAction::List => {
                     lib.list_songs();
                 }
             },
         },
+        cli::Commands::Albums { action } => match action {
+            None | Some(cli::AlbumAction::List) => lib.list_albums(),
+            Some(cli::AlbumAction::View { album_name }) => lib.view_album(album_name),
+        },
+        cli::Commands::Artists { action } => match action {
+            None | Some(cli::ArtistAction::List) => lib.list_artists(),
+            Some(cli::ArtistAction::View { artist_name }) => lib.view_artist(artist_name),
+        },
         cli::Commands::Playlists { action } => match action {
             None => lib.list_playlists(),
             Some(command) => match command {
@@ -133,11 +283,86 @@ fn main() {
         },
     }
 
-    if let Err(_) = lib.save() {
-        println!("Error while saving data");
+    if let Err(e) = lib.save() {
+        println!("Error while saving data: {e}");
+    };
+}
+
+/// Scans `directory`, or every configured root without one.
+fn scan(lib: &mut MusicLibrary, directory: Option<String>, args: cli::ScanArgs) {
+    let config = load_config();
+    let options = scan_options(args, &config);
+    lib.scan_directories(&scan_roots(directory, &config), &options);
+}
+
+fn scan_roots(directory: Option<String>, config: &Config) -> Vec<String> {
+    match directory {
+        Some(directory) => vec![directory],
+        None if config.library.roots.is_empty() => {
+            eprintln!(
+                "No directory given and no library roots configured in {}",
+                config::get_config_file_path().display()
+            );
+            process::exit(1);
+        }
+        None => config.library.roots.clone(),
+    }
+}
+
+/// Combines scan flags with the library section of the config file, exiting
+/// when an exclude pattern is invalid.
+fn scan_options(args: cli::ScanArgs, config: &Config) -> ScanOptions {
+    let mut exclude = config.library.exclude.clone();
+    exclude.extend(args.exclude);
+    let options = ScanOptions {
+        exclude,
+        follow_links: args.follow_symlinks || config.library.follow_symlinks,
+        max_depth: args.max_depth.or(config.library.max_depth),
     };
+    if let Err(e) = options.validate() {
+        eprintln!("{e}");
+        process::exit(2);
+    }
+    options
+}
+
+fn load_config() -> Config {
+    match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading config: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn load_queue() -> Queue {
+    match Queue::load() {
+        Ok(queue) => queue,
+        Err(e) if e.kind() == ErrorKind::NotFound => Queue::new(),
+        Err(e) => {
+            eprintln!("Error loading queue: {e}");
+            process::exit(1);
+        }
+    }
+}
 
-    if let Err(_) = queue.save() {
-        println!("Error while saving data");
+fn update_queue(f: impl FnOnce(&mut Queue)) {
+    if let Err(e) = Queue::update(f) {
+        println!("Error while updating queue: {e}");
     };
 }
+
+/// Drops songs removed from the library from the queue.
+fn dequeue(songs: &[Song]) {
+    if songs.is_empty() {
+        return;
+    }
+    update_queue(|queue| {
+        for song in songs {
+            if queue.remove_id(&song.id) > 0 {
+                println!("Removed {} from the queue", song.name);
+            }
+        }
+    });
+}
@@ -1,24 +1,167 @@
-use crate::cli::{EditPlaylist, EditSong};
-use crate::config;
+use crate::duration;
+use crate::loudness::{self, Measurement};
+use crate::progress::Progress;
+use crate::storage;
+use crate::tags;
+use clap::{Subcommand, ValueEnum};
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::{Walk, WalkBuilder};
 use lofty::file::{AudioFile, TaggedFileExt};
 use lofty::probe::Probe;
-use lofty::tag::ItemKey;
+use lofty::tag::{Accessor, ItemKey, Tag};
+use rayon::prelude::*;
 use rodio::Decoder;
-use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::HashMap;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, BufReader, Read, Write};
-use std::path::Path;
-use walkdir::WalkDir;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+/// Lists paths a scan should skip, in gitignore syntax
+pub const MUSIC_IGNORE_FILE: &str = ".musicignore";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Song {
+    #[serde(default)]
+    pub id: String,
     pub name: String,
     pub artist: String,
     pub album: String,
     pub duration: u64, //seconds
     pub path: String,
+    /// ReplayGain adjustments in dB, read from tags or `loudness analyze`
+    #[serde(default)]
+    pub track_gain: Option<f32>,
+    #[serde(default)]
+    pub album_gain: Option<f32>,
+    /// Highest sample levels as a fraction of full scale, which ReplayGain
+    /// adjustments are limited by so they don't clip
+    #[serde(default)]
+    pub track_peak: Option<f32>,
+    #[serde(default)]
+    pub album_peak: Option<f32>,
+    #[serde(default)]
+    pub track: Option<u32>,
+    #[serde(default)]
+    pub disc: Option<u32>,
+    #[serde(default)]
+    pub year: Option<u32>,
+    #[serde(default)]
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub album_artist: Option<String>,
+    #[serde(default)]
+    pub composer: Option<String>,
+    /// Audio bitrate in kbps
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    /// Sample rate in Hz
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    #[serde(default)]
+    pub channels: Option<u8>,
+    /// File modification time in nanoseconds since the Unix epoch, and size
+    /// in bytes, when the file was last read
+    #[serde(default)]
+    pub modified: Option<u64>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// BLAKE3 hash of the file's contents, used to recognise it after a move
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Subcommand, ValueEnum)]
+pub enum EditSong {
+    Name,
+    Artist,
+    Album,
+    Path,
+    Track,
+    Disc,
+    Year,
+    Genre,
+    AlbumArtist,
+    Composer,
+}
+
+impl fmt::Display for EditSong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditSong::Name => write!(f, "name"),
+            EditSong::Artist => write!(f, "artist"),
+            EditSong::Album => write!(f, "album"),
+            EditSong::Path => write!(f, "path"),
+            EditSong::Track => write!(f, "track"),
+            EditSong::Disc => write!(f, "disc"),
+            EditSong::Year => write!(f, "year"),
+            EditSong::Genre => write!(f, "genre"),
+            EditSong::AlbumArtist => write!(f, "album-artist"),
+            EditSong::Composer => write!(f, "composer"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum EditPlaylist {
+    Name,
+}
+
+/// A new value for one song field, checked when created. An empty value
+/// clears an optional field.
+#[derive(Debug, Clone)]
+pub struct FieldEdit {
+    pub field: EditSong,
+    pub value: String,
+}
+
+impl FieldEdit {
+    pub fn new(field: EditSong, value: String) -> Result<Self, String> {
+        if matches!(field, EditSong::Track | EditSong::Disc | EditSong::Year) {
+            optional_number(&value)?;
+        }
+        Ok(FieldEdit { field, value })
+    }
+
+    pub fn apply(&self, song: &mut Song) {
+        let value = self.value.clone();
+        let number = optional_number(&value).ok().flatten();
+        match self.field {
+            EditSong::Name => song.name = value,
+            EditSong::Artist => song.artist = value,
+            EditSong::Album => song.album = value,
+            EditSong::Path => song.path = value,
+            EditSong::Track => song.track = number,
+            EditSong::Disc => song.disc = number,
+            EditSong::Year => song.year = number,
+            EditSong::Genre => song.genre = optional_text(value),
+            EditSong::AlbumArtist => song.album_artist = optional_text(value),
+            EditSong::Composer => song.composer = optional_text(value),
+        }
+    }
+}
+
+/// Selects songs whose field equals a value or, with `contains`, includes
+/// it ignoring case. Unset fields compare as empty.
+#[derive(Debug, Clone)]
+pub struct SongFilter {
+    pub field: EditSong,
+    pub value: String,
+    pub contains: bool,
+}
+
+impl SongFilter {
+    pub fn matches(&self, song: &Song) -> bool {
+        let actual = tags::field_value(song, self.field).unwrap_or_default();
+        if self.contains {
+            actual.to_lowercase().contains(&self.value.to_lowercase())
+        } else {
+            actual == self.value
+        }
+    }
 }
 
 impl Song {
@@ -29,16 +172,161 @@ impl Song {
     }
 }
 
+/// An album, derived from the album name and album artist of its songs.
+#[derive(Debug, Clone)]
+pub struct Album<'a> {
+    pub name: String,
+    /// The album artist, or the track artist when the songs don't have one
+    pub artist: String,
+    /// In disc and track order
+    pub songs: Vec<&'a Song>,
+}
+
+impl Album<'_> {
+    pub fn runtime(&self) -> Duration {
+        Duration::from_secs(self.songs.iter().map(|song| song.duration).sum())
+    }
+
+    pub fn year(&self) -> Option<u32> {
+        self.songs.iter().filter_map(|song| song.year).min()
+    }
+}
+
+/// An artist, derived from the track artist of their songs.
+#[derive(Debug, Clone)]
+pub struct Artist<'a> {
+    pub name: String,
+    /// In album, disc and track order
+    pub songs: Vec<&'a Song>,
+}
+
+/// Which files a scan looks at.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Glob patterns in gitignore syntax, relative to the scanned directory
+    pub exclude: Vec<String>,
+    pub follow_links: bool,
+    /// How deep to look, where 1 means only files directly in the directory
+    pub max_depth: Option<usize>,
+}
+
+impl ScanOptions {
+    /// Checks that the exclude patterns are valid globs.
+    pub fn validate(&self) -> Result<(), String> {
+        self.overrides(Path::new("/")).map(|_| ())
+    }
+
+    fn overrides(&self, root: &Path) -> Result<Override, String> {
+        let mut overrides = OverrideBuilder::new(root);
+        for pattern in &self.exclude {
+            // an override without `!` would include only what matches
+            overrides.add(&format!("!{pattern}")).map_err(|e| {
+                let reason = match e {
+                    ignore::Error::Glob { err, .. } => err,
+                    e => e.to_string(),
+                };
+                format!("Invalid exclude pattern '{pattern}': {reason}")
+            })?;
+        }
+        overrides.build().map_err(|e| e.to_string())
+    }
+
+    /// Walks `root` in path order, skipping excluded files and anything
+    /// listed in `.musicignore` files, which use gitignore syntax.
+    fn walker(&self, root: &Path) -> Result<Walk, String> {
+        Ok(WalkBuilder::new(root)
+            .standard_filters(false)
+            .add_custom_ignore_filename(MUSIC_IGNORE_FILE)
+            .parents(true)
+            .overrides(self.overrides(root)?)
+            .follow_links(self.follow_links)
+            .max_depth(self.max_depth)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build())
+    }
+}
+
+/// What a scan found out about one file.
+enum Scanned {
+    /// A known song whose file hasn't changed
+    Unchanged(String),
+    /// Freshly read metadata, with the ID of the song already at the path
+    Read(Option<String>, Box<Song>),
+    Failed,
+}
+
+/// Why a song's file can't be played.
+#[derive(Debug)]
+pub enum Problem {
+    Missing,
+    Unreadable(io::Error),
+    Undecodable,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::Unreadable(e) => write!(f, "unreadable ({e})"),
+            Problem::Undecodable => write!(f, "undecodable"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Playlist {
     pub name: String,
-    pub songs: Vec<Song>,
+    /// IDs of the playlist's songs, resolved against the library when read
+    #[serde(deserialize_with = "deserialize_song_ids")]
+    pub songs: Vec<String>,
+}
+
+/// A saved playlist or queue entry. Older versions stored whole songs
+/// instead of IDs, and before that songs had no ID at all.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SongRef {
+    Id(String),
+    Song {
+        #[serde(default)]
+        id: String,
+        path: String,
+    },
+}
+
+/// Reads playlist or queue entries as song IDs, accepting the whole songs
+/// saved by older versions.
+pub fn deserialize_song_ids<'de, D, C>(deserializer: D) -> Result<C, D::Error>
+where
+    D: Deserializer<'de>,
+    C: FromIterator<String>,
+{
+    let entries = Vec::<SongRef>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| match entry {
+            SongRef::Id(id) => id,
+            SongRef::Song { id, path } if id.is_empty() => song_id(Path::new(&path)),
+            SongRef::Song { id, .. } => id,
+        })
+        .collect())
+}
+
+/// Song IDs and playlist names modified since the library was loaded, so
+/// storage backends can persist only what changed. An entry that is no
+/// longer present in the library was removed.
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    pub songs: HashSet<String>,
+    pub playlists: HashSet<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MusicLibrary {
     pub songs: HashMap<String, Song>,
     pub playlists: HashMap<String, Playlist>,
+    #[serde(skip)]
+    pub changes: Changes,
 }
 
 impl MusicLibrary {
@@ -46,50 +334,345 @@ impl MusicLibrary {
         MusicLibrary {
             songs: HashMap::new(),
             playlists: HashMap::new(),
+            changes: Changes::default(),
         }
     }
 
     pub fn save(&self) -> io::Result<()> {
-        let file_name = config::get_library_file_path();
-        if let Some(parent) = file_name.parent() {
-            std::fs::create_dir_all(parent)?;
+        storage::open()?.save_library(self)
+    }
+
+    pub fn load() -> io::Result<Self> {
+        storage::open()?.load_library()
+    }
+
+    pub fn load_song(id: &str) -> io::Result<Option<Song>> {
+        storage::open()?.load_song(id)
+    }
+
+    /// Adds new files under `directories` and re-reads those whose
+    /// modification time or size changed. A new file with the same contents
+    /// as a vanished song is taken to be that song moved, and the song is
+    /// relinked to it, keeping its ID, even when it moved from one directory
+    /// to another. Songs whose files are gone are only counted, not removed,
+    /// since a directory may be empty just because a drive isn't mounted;
+    /// `prune` removes them.
+    ///
+    /// Files are read in parallel, but results are applied in path order so
+    /// the outcome and output don't depend on scheduling. Songs that are now
+    /// excluded by `options` are left in the library.
+    pub fn scan_directories(&mut self, directories: &[String], options: &ScanOptions) {
+        let mut roots = Vec::new();
+        for directory in directories {
+            match fs::canonicalize(directory) {
+                Ok(root) => roots.push(root),
+                Err(_) => println!("Unable to read '{directory}'"),
+            }
+        }
+        if roots.is_empty() {
+            return;
+        }
+
+        let mut walkers = Vec::new();
+        for root in &roots {
+            match options.walker(root) {
+                Ok(walker) => walkers.push(walker),
+                Err(e) => {
+                    println!("{e}");
+                    return;
+                }
+            }
+        }
+
+        let progress = Progress::start();
+        let mut paths = Vec::new();
+        for walker in walkers {
+            for entry in walker
+                .filter_map(|e| e.ok())
+                .filter(|e| is_music_file(e.path()))
+            {
+                if let Ok(path) = fs::canonicalize(entry.path()) {
+                    progress.found();
+                    paths.push(path);
+                }
+            }
+        }
+        // roots may overlap
+        paths.sort();
+        paths.dedup();
+
+        let by_path: HashMap<String, String> = self
+            .songs
+            .values()
+            .map(|song| (song.path.clone(), song.id.clone()))
+            .collect();
+        let results: Vec<(PathBuf, Scanned)> = paths
+            .into_par_iter()
+            .map(|path| {
+                let result = self.scan_file(&path, &by_path);
+                progress.processed(
+                    matches!(result, Scanned::Unchanged(_)),
+                    matches!(result, Scanned::Failed),
+                );
+                (path, result)
+            })
+            .collect();
+        progress.finish();
+
+        let (mut added, mut updated, mut moved, mut unchanged) = (0, 0, 0, 0);
+        let mut seen = HashSet::new();
+        let mut new_songs = Vec::new();
+        let mut failed = Vec::new();
+        for (path, result) in results {
+            match result {
+                Scanned::Unchanged(id) => {
+                    seen.insert(id);
+                    unchanged += 1;
+                }
+                Scanned::Read(Some(id), mut song) => {
+                    let old = &self.songs[&id];
+                    song.id = id.clone();
+                    // keep measured loudness unless the tags now carry their own
+                    song.track_gain = song.track_gain.or(old.track_gain);
+                    song.album_gain = song.album_gain.or(old.album_gain);
+                    song.track_peak = song.track_peak.or(old.track_peak);
+                    song.album_peak = song.album_peak.or(old.album_peak);
+                    self.insert_song(*song);
+                    seen.insert(id);
+                    updated += 1;
+                }
+                Scanned::Read(None, song) => new_songs.push(*song),
+                Scanned::Failed => failed.push(path),
+            }
+        }
+
+        let mut vanished: Vec<&Song> = self
+            .songs
+            .values()
+            .filter(|song| {
+                let path = Path::new(&song.path);
+                roots.iter().any(|root| path.starts_with(root))
+                    && !seen.contains(&song.id)
+                    && !path.exists()
+            })
+            .collect();
+        vanished.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut vanished: Vec<String> = vanished.into_iter().map(|song| song.id.clone()).collect();
+
+        for mut song in new_songs {
+            let original = vanished
+                .iter()
+                .position(|id| same_file(&self.songs[id], &song));
+            match original {
+                Some(index) => {
+                    let id = vanished.remove(index);
+                    if let Some(old) = self.songs.get_mut(&id) {
+                        println!("Moved {} to {}", old.path, song.path);
+                        old.path = song.path;
+                        old.modified = song.modified;
+                        old.size = song.size;
+                        old.hash = song.hash;
+                        self.changes.songs.insert(id);
+                    }
+                    moved += 1;
+                }
+                None => {
+                    song.id = self.free_id(&song.path);
+                    self.insert_song(song);
+                    added += 1;
+                }
+            }
         }
 
-        let file = File::create(file_name)?;
-        let json_data = serde_json::to_string_pretty(self)?;
-        let mut writer = io::BufWriter::new(file);
-        writer.write_all(json_data.as_bytes())?;
-        Ok(())
+        for path in &failed {
+            println!("Unable to read '{}'", path.display());
+        }
+        let roots: Vec<String> = roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect();
+        println!(
+            "Scanned {}: {added} added, {updated} updated, {moved} moved, {} missing, \
+             {unchanged} unchanged, {} failed",
+            roots.join(", "),
+            vanished.len(),
+            failed.len()
+        );
+        if !vanished.is_empty() {
+            println!("Songs whose files are missing can be removed with `library prune`");
+        }
     }
 
-    pub fn load() -> io::Result<Self> {
-        let file_name = config::get_library_file_path();
-        let mut file = File::open(file_name)?;
-        let mut json_data = String::new();
-        file.read_to_string(&mut json_data)?;
-        let library: MusicLibrary = serde_json::from_str(&json_data)?;
-        Ok(library)
+    /// Reads a file found by a scan unless it is unchanged since it was last
+    /// read. `by_path` maps known paths to song IDs.
+    fn scan_file(&self, path: &Path, by_path: &HashMap<String, String>) -> Scanned {
+        let id = by_path.get(path.to_string_lossy().as_ref());
+        if let (Some(id), Some((modified, size))) = (id, file_stamp(path)) {
+            let song = &self.songs[id];
+            if song.modified == Some(modified) && song.size == Some(size) {
+                return Scanned::Unchanged(id.clone());
+            }
+        }
+        match read_metadata(path) {
+            Some(song) => Scanned::Read(id.cloned(), Box::new(song)),
+            None => Scanned::Failed,
+        }
     }
 
-    pub fn scan_directory(&mut self, directory: &String) {
-        for entry in WalkDir::new(directory)
+    /// Finds songs whose files are missing or can't be played, ordered by
+    /// path. A file is only missing when its directory is still there;
+    /// otherwise the drive or share holding it may just not be mounted, so
+    /// it counts as unreadable.
+    pub fn find_problems(&self) -> Vec<(&Song, Problem)> {
+        let mut songs: Vec<&Song> = self.songs.values().collect();
+        songs.sort_by(|a, b| a.path.cmp(&b.path));
+        songs
             .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| is_music_file(e.path()))
-        {
-            let path = entry.path();
+            .filter_map(|song| {
+                let problem = match File::open(&song.path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        match Path::new(&song.path).parent() {
+                            Some(dir) if !dir.is_dir() => Problem::Unreadable(io::Error::new(
+                                io::ErrorKind::NotFound,
+                                format!("{} is gone", dir.display()),
+                            )),
+                            _ => Problem::Missing,
+                        }
+                    }
+                    Err(e) => Problem::Unreadable(e),
+                    Ok(file) => match Decoder::new(BufReader::new(file)) {
+                        Ok(_) => return None,
+                        Err(_) => Problem::Undecodable,
+                    },
+                };
+                Some((song, problem))
+            })
+            .collect()
+    }
 
-            if !is_music_file(&path) {
-                continue;
+    pub fn check(&self) {
+        let problems = self.find_problems();
+        for (song, problem) in &problems {
+            println!("{problem}: {} ({})", song.path, song.name);
+        }
+        if problems.is_empty() {
+            println!("All {} songs are playable", self.songs.len());
+        } else {
+            println!(
+                "{} of {} songs have problems",
+                problems.len(),
+                self.songs.len()
+            );
+        }
+    }
+
+    /// Removes songs whose files are missing or can't be decoded, or only
+    /// lists them with `dry_run`. Unreadable files, such as those without
+    /// permission to read them or on a share that isn't mounted, are only
+    /// removed with `unreadable`. Returns the removed songs so callers can
+    /// clean up other references.
+    pub fn prune(&mut self, dry_run: bool, unreadable: bool) -> Vec<Song> {
+        let mut kept = 0;
+        let mut problems: Vec<(String, String)> = Vec::new();
+        for (song, problem) in self.find_problems() {
+            if matches!(problem, Problem::Unreadable(_)) && !unreadable {
+                kept += 1;
+            } else {
+                problems.push((song.id.clone(), problem.to_string()));
             }
+        }
+        match kept {
+            0 => {}
+            1 => println!("Keeping 1 unreadable song, use --unreadable to remove it"),
+            _ => println!("Keeping {kept} unreadable songs, use --unreadable to remove them"),
+        }
+        if problems.is_empty() {
+            println!("Nothing to prune");
+            return Vec::new();
+        }
 
-            if !self
-                .songs
-                .values()
-                .any(|song| song.path == path.to_string_lossy().to_string())
-            {
-                if let Some(song) = read_metadata(path) {
-                    self.songs.insert(song.name.clone(), song);
+        let mut removed = Vec::new();
+        for (id, problem) in problems {
+            let song = &self.songs[&id];
+            if dry_run {
+                println!("Would remove {} ({problem}: {})", song.name, song.path);
+            } else if let Some(song) = self.delete_song(&id) {
+                println!("Removed {} ({problem}: {})", song.name, song.path);
+                removed.push(song);
+            }
+        }
+        removed
+    }
+
+    /// Measures the loudness of songs without ReplayGain values, or of every
+    /// song with `force`. Albums are measured as a whole, so every song on an
+    /// album missing a value is analyzed again.
+    pub fn analyze_loudness(&mut self, force: bool) {
+        let albums: HashSet<(&str, &str)> = self
+            .songs
+            .values()
+            .filter(|song| force || song.track_gain.is_none() || song.album_gain.is_none())
+            .map(album_key)
+            .collect();
+        let mut groups: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+        for song in self.songs.values() {
+            let (artist, album) = album_key(song);
+            if albums.contains(&(artist, album)) {
+                groups
+                    .entry((artist.to_string(), album.to_string()))
+                    .or_default()
+                    .push(song.id.clone());
+            }
+        }
+        if groups.is_empty() {
+            println!("Every song already has ReplayGain values");
+            return;
+        }
+
+        for ((artist, album), mut ids) in groups {
+            ids.sort_by(|a, b| (&self.songs[a].name, a).cmp(&(&self.songs[b].name, b)));
+
+            let mut total = Measurement::default();
+            let mut measured = Vec::new();
+            for id in ids {
+                let song = &self.songs[&id];
+                let Some(source) = song.get_source() else {
+                    println!("Unable to decode '{}'", song.path);
+                    continue;
+                };
+                let measurement = loudness::measure(source);
+                match measurement.loudness() {
+                    Some(lufs) => println!(
+                        "{} by {}: {lufs:.1} LUFS, track gain {:+.2} dB",
+                        song.name,
+                        song.artist,
+                        loudness::REFERENCE_LOUDNESS - lufs
+                    ),
+                    None => println!("{} by {} is silent", song.name, song.artist),
+                }
+                total.extend(&measurement);
+                measured.push((id, measurement.gain(), measurement.peak()));
+            }
+
+            // songs without an album aren't measured together
+            let unknown = album == "Unknown";
+            let album_gain = total.gain();
+            let album_peak = total.peak();
+            if !unknown {
+                if let Some(gain) = album_gain {
+                    println!("Album '{album}' by {artist}: album gain {gain:+.2} dB");
+                }
+            }
+            for (id, track_gain, track_peak) in measured {
+                if let Some(song) = self.songs.get_mut(&id) {
+                    // values read from tags are kept unless forced
+                    if force || song.track_gain.is_none() {
+                        song.track_gain = track_gain;
+                    }
+                    song.album_gain = if unknown { track_gain } else { album_gain };
+                    song.track_peak = Some(track_peak);
+                    song.album_peak = Some(if unknown { track_peak } else { album_peak });
+                    self.changes.songs.insert(id);
                 }
             }
         }
@@ -99,57 +682,367 @@ impl MusicLibrary {
 
     pub fn list_songs(&self) {
         self.songs
-            .iter()
-            .for_each(|(song_name, _)| println!("{}", song_name));
+            .values()
+            .for_each(|song| println!("{} ({})", song.name, song.id));
     }
 
     pub fn add_song(&mut self, path: String) {
         let path = Path::new(&path);
 
-        if let Some(song) = read_metadata(path) {
-            if self.songs.contains_key(&song.name) {
+        if let Some(mut song) = read_metadata(path) {
+            if self
+                .songs
+                .values()
+                .any(|existing| existing.path == song.path)
+            {
                 println!("Song '{}' already exists in the library.", song.name);
             } else {
-                self.songs.insert(song.name.clone(), song);
+                song.id = self.free_id(&song.path);
+                self.insert_song(song);
             }
         }
     }
 
     pub fn view_song(&self, name: String) {
-        if let Some(song) = self.songs.get(&name) {
-            println!("Name:      {}", song.name);
-            println!("Artist:    {}", song.artist);
-            println!("Album:     {}", song.album);
-            println!("Duration:  {} seconds", song.duration);
-            println!("File Path: {}", song.path);
+        if let Some(song) = self.get_song(name.clone()) {
+            println!("ID:           {}", song.id);
+            println!("Name:         {}", song.name);
+            println!("Artist:       {}", song.artist);
+            println!("Album:        {}", song.album);
+            if let Some(album_artist) = &song.album_artist {
+                println!("Album Artist: {album_artist}");
+            }
+            if let Some(composer) = &song.composer {
+                println!("Composer:     {composer}");
+            }
+            if let Some(genre) = &song.genre {
+                println!("Genre:        {genre}");
+            }
+            if let Some(year) = song.year {
+                println!("Year:         {year}");
+            }
+            if let Some(track) = song.track {
+                println!("Track:        {track}");
+            }
+            if let Some(disc) = song.disc {
+                println!("Disc:         {disc}");
+            }
+            println!("Duration:     {} seconds", song.duration);
+            if let Some(bitrate) = song.bitrate {
+                println!("Bitrate:      {bitrate} kbps");
+            }
+            if let Some(sample_rate) = song.sample_rate {
+                println!("Sample Rate:  {sample_rate} Hz");
+            }
+            if let Some(channels) = song.channels {
+                println!("Channels:     {channels}");
+            }
+            println!("File Path:    {}", song.path);
+            if let Some(gain) = song.track_gain {
+                println!("Track Gain:   {gain:+.2} dB");
+            }
+            if let Some(gain) = song.album_gain {
+                println!("Album Gain:   {gain:+.2} dB");
+            }
         } else {
             println!("'{}' not found", name);
         }
     }
 
-    pub fn edit_song(&mut self, name: String, field: EditSong, value: String) {
-        if let Some(song) = self.songs.get_mut(&name) {
-            match field {
-                EditSong::Name => song.name = value,
-                EditSong::Artist => song.artist = value,
-                EditSong::Album => song.album = value,
-                EditSong::Path => song.path = value,
+    /// Changes one field of a song. With `write_tags` the change is written
+    /// to the file's tags first, and the song is left alone if that fails.
+    /// A dry run only shows what would change.
+    ///
+    /// A song given a new path keeps its ID, just like one a scan finds
+    /// moved.
+    pub fn edit_song(
+        &mut self,
+        name: String,
+        field: EditSong,
+        value: String,
+        write_tags: bool,
+        dry_run: bool,
+    ) {
+        // stored like scanned paths, so a scan finds the song at its new path
+        let value = match field {
+            EditSong::Path => match fs::canonicalize(&value) {
+                Ok(path) => path.to_string_lossy().into_owned(),
+                Err(e) => {
+                    println!("Unable to read '{value}': {e}");
+                    return;
+                }
+            },
+            _ => value,
+        };
+        let edit = match FieldEdit::new(field, value) {
+            Ok(edit) => edit,
+            Err(e) => {
+                println!("{e}");
+                return;
+            }
+        };
+
+        let Some(song) = self.get_song(name.clone()) else {
+            println!("Song '{name}' not found");
+            return;
+        };
+        let mut edited = song.clone();
+        edit.apply(&mut edited);
+        let moved = edited.path != song.path;
+        if moved {
+            if let Some(other) = self.songs.values().find(|other| other.path == edited.path) {
+                println!("'{}' already belongs to {}", other.path, other.name);
+                return;
             }
-        } else {
-            println!("Song '{name} not found")
         }
+
+        if dry_run {
+            preview_edit(song, &edited, write_tags);
+            return;
+        }
+        if write_tags && !write_song_tags(&mut edited) {
+            return;
+        }
+        if moved {
+            // so the next scan doesn't re-read the file and undo other edits
+            refresh_stamp(&mut edited);
+        }
+        self.insert_song(edited);
     }
 
-    pub fn remove_song(&mut self, name: String) {
-        if self.songs.remove(&name).is_some() {
-            println!("Removed {name} from library");
+    /// Applies `edits` to every song matching all `filters`. The changes are
+    /// previewed and, unless `yes` is given, only made once confirmed. With
+    /// `write_tags`, songs whose tags cannot be written are left alone.
+    pub fn edit_songs(
+        &mut self,
+        filters: &[SongFilter],
+        edits: &[FieldEdit],
+        write_tags: bool,
+        dry_run: bool,
+        yes: bool,
+    ) {
+        let mut matches: Vec<&Song> = self
+            .songs
+            .values()
+            .filter(|song| filters.iter().all(|filter| filter.matches(song)))
+            .collect();
+        if matches.is_empty() {
+            println!("No songs match");
+            return;
+        }
+        matches.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut unchanged = 0;
+        let mut edited = Vec::new();
+        for song in matches {
+            let mut new = song.clone();
+            for edit in edits {
+                edit.apply(&mut new);
+            }
+            if new == *song {
+                unchanged += 1;
+            } else {
+                preview_edit(song, &new, write_tags);
+                edited.push(new);
+            }
+        }
+        match unchanged {
+            0 => {}
+            1 => println!("1 matching song already has these values"),
+            _ => println!("{unchanged} matching songs already have these values"),
+        }
+        if edited.is_empty() || dry_run {
+            return;
+        }
+        if !yes && !confirm(&format!("Edit {}?", count_songs(edited.len()))) {
+            println!("No songs edited");
+            return;
+        }
+
+        let total = edited.len();
+        let mut failed = 0;
+        for mut song in edited {
+            if write_tags && !write_song_tags(&mut song) {
+                failed += 1;
+                continue;
+            }
+            self.changes.songs.insert(song.id.clone());
+            self.songs.insert(song.id.clone(), song);
+        }
+        println!("Edited {} of {}", total - failed, count_songs(total));
+    }
+
+    /// Removes a song along with every playlist entry referencing it.
+    /// Returns the removed song so callers can clean up other references.
+    pub fn remove_song(&mut self, name: String) -> Option<Song> {
+        let id = self.get_song(name.clone()).map(|song| song.id.clone());
+        if let Some(song) = id.and_then(|id| self.delete_song(&id)) {
+            println!("Removed {} from library", song.name);
+            Some(song)
         } else {
             println!("{name} does not exist in library");
+            None
+        }
+    }
+
+    /// Removes the song with the given ID from the library and its playlists.
+    fn delete_song(&mut self, id: &str) -> Option<Song> {
+        let song = self.songs.remove(id)?;
+        self.changes.songs.insert(song.id.clone());
+        for playlist in self.playlists.values_mut() {
+            let before = playlist.songs.len();
+            playlist.songs.retain(|id| *id != song.id);
+            let removed = before - playlist.songs.len();
+            if removed > 0 {
+                println!("Removed {} from playlist '{}'", song.name, playlist.name);
+                self.changes.playlists.insert(playlist.name.clone());
+            }
         }
+        Some(song)
+    }
+
+    fn insert_song(&mut self, song: Song) {
+        self.changes.songs.insert(song.id.clone());
+        self.songs.insert(song.id.clone(), song);
     }
 
+    /// An ID for a new song at `path`. A song moved away from the path keeps
+    /// the ID derived from it, so the new one may need another.
+    fn free_id(&self, path: &str) -> String {
+        let mut id = song_id(Path::new(path));
+        let mut attempt = 1;
+        while self.songs.contains_key(&id) {
+            id = song_id(Path::new(&format!("{path}#{attempt}")));
+            attempt += 1;
+        }
+        id
+    }
+
+    /// Looks a song up by ID, falling back to its name. When several songs
+    /// share the name, the user is asked to pick one.
     pub fn get_song(&self, song_name: String) -> Option<&Song> {
-        self.songs.get(&song_name)
+        if let Some(song) = self.songs.get(&song_name) {
+            return Some(song);
+        }
+        choose_song(&song_name, self.songs.values())
+    }
+
+    // ALBUMS
+
+    /// Every album in the library, ordered by artist and name.
+    pub fn albums(&self) -> Vec<Album<'_>> {
+        let mut albums: BTreeMap<(&str, &str), Vec<&Song>> = BTreeMap::new();
+        for song in self.songs.values() {
+            albums.entry(album_key(song)).or_default().push(song);
+        }
+        albums
+            .into_iter()
+            .map(|((artist, name), mut songs)| {
+                songs.sort_by(|a, b| album_order(a, b));
+                Album {
+                    name: name.to_string(),
+                    artist: artist.to_string(),
+                    songs,
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_album(&self, name: String) -> Option<Album<'_>> {
+        let matches = self
+            .albums()
+            .into_iter()
+            .filter(|album| album.name == name)
+            .collect();
+        choose("album", &name, matches, |album| {
+            format!("{} - {}", album.name, album.artist)
+        })
+    }
+
+    pub fn list_albums(&self) {
+        for album in self.albums() {
+            println!(
+                "{} by {} ({} songs, {})",
+                album.name,
+                album.artist,
+                album.songs.len(),
+                duration::format(album.runtime())
+            );
+        }
+    }
+
+    pub fn view_album(&self, name: String) {
+        if let Some(album) = self.get_album(name.clone()) {
+            println!("Album:   {}", album.name);
+            println!("Artist:  {}", album.artist);
+            if let Some(year) = album.year() {
+                println!("Year:    {year}");
+            }
+            println!("Runtime: {}", duration::format(album.runtime()));
+            println!("Tracks:");
+            print_tracks(&album.songs);
+        } else {
+            println!("'{}' not found", name);
+        }
+    }
+
+    // ARTISTS
+
+    /// Every artist in the library, ordered by name.
+    pub fn artists(&self) -> Vec<Artist<'_>> {
+        let mut artists: BTreeMap<&str, Vec<&Song>> = BTreeMap::new();
+        for song in self.songs.values() {
+            artists.entry(&song.artist).or_default().push(song);
+        }
+        artists
+            .into_iter()
+            .map(|(name, mut songs)| {
+                songs.sort_by(|a, b| a.album.cmp(&b.album).then_with(|| album_order(a, b)));
+                Artist {
+                    name: name.to_string(),
+                    songs,
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_artist(&self, name: String) -> Option<Artist<'_>> {
+        self.artists()
+            .into_iter()
+            .find(|artist| artist.name == name)
+    }
+
+    pub fn list_artists(&self) {
+        for artist in self.artists() {
+            let albums: HashSet<&str> = artist
+                .songs
+                .iter()
+                .map(|song| song.album.as_str())
+                .collect();
+            println!(
+                "{} ({} albums, {} songs)",
+                artist.name,
+                albums.len(),
+                artist.songs.len()
+            );
+        }
+    }
+
+    pub fn view_artist(&self, name: String) {
+        if let Some(artist) = self.get_artist(name.clone()) {
+            println!("Artist:  {}", artist.name);
+            println!("Albums:");
+            for songs in artist.songs.chunk_by(|a, b| a.album == b.album) {
+                let album = &songs[0].album;
+                match songs.iter().filter_map(|song| song.year).min() {
+                    Some(year) => println!("  {album} ({year})"),
+                    None => println!("  {album}"),
+                }
+                print_tracks(songs);
+            }
+        } else {
+            println!("'{}' not found", name);
+        }
     }
 
     // PLAYLISTS
@@ -161,6 +1054,7 @@ impl MusicLibrary {
     }
 
     pub fn create_playlist(&mut self, name: String) {
+        self.changes.playlists.insert(name.clone());
         self.playlists.insert(
             name.clone(),
             Playlist {
@@ -177,7 +1071,10 @@ impl MusicLibrary {
             playlist
                 .songs
                 .iter()
-                .for_each(|song| println!("       {0} - {1}", song.name, song.artist));
+                .for_each(|id| match self.songs.get(id) {
+                    Some(song) => println!("       {0} - {1}", song.name, song.artist),
+                    None => println!("       <missing song '{id}'>"),
+                });
         } else {
             println!("'{}' not found", name);
         }
@@ -185,8 +1082,13 @@ impl MusicLibrary {
 
     pub fn add_song_playlist(&mut self, name: String, song_name: String) {
         if let Some(playlist) = self.playlists.get_mut(&name) {
-            if let Some(song) = self.songs.get(&song_name) {
-                playlist.songs.push(song.clone());
+            let song = match self.songs.get(&song_name) {
+                Some(song) => Some(song),
+                None => choose_song(&song_name, self.songs.values()),
+            };
+            if let Some(song) = song {
+                playlist.songs.push(song.id.clone());
+                self.changes.playlists.insert(name);
             } else {
                 println!("{song_name} does not exist in library.");
             }
@@ -196,9 +1098,15 @@ impl MusicLibrary {
     }
 
     pub fn edit_playist(&mut self, name: String, field: EditPlaylist, value: String) {
-        if let Some(playlist) = self.playlists.get_mut(&name) {
+        if let Some(mut playlist) = self.playlists.remove(&name) {
+            self.changes.playlists.insert(name);
             match field {
-                EditPlaylist::Name => playlist.name = value,
+                EditPlaylist::Name => {
+                    // playlists are keyed by name
+                    playlist.name = value;
+                    self.changes.playlists.insert(playlist.name.clone());
+                    self.playlists.insert(playlist.name.clone(), playlist);
+                }
             }
         } else {
             println!("Song '{name}' not found")
@@ -207,13 +1115,16 @@ impl MusicLibrary {
 
     pub fn remove_playlist_song(&mut self, name: String, song_name: String) {
         if let Some(playlist) = self.playlists.get_mut(&name) {
-            if let Some(index) = playlist
-                .songs
-                .iter()
-                .position(|song| song.name == song_name)
-            {
+            let id = if playlist.songs.contains(&song_name) {
+                Some(song_name.clone())
+            } else {
+                let songs = playlist.songs.iter().filter_map(|id| self.songs.get(id));
+                choose_song(&song_name, songs).map(|song| song.id.clone())
+            };
+            if let Some(index) = id.and_then(|id| playlist.songs.iter().position(|s| *s == id)) {
                 playlist.songs.swap_remove(index);
                 println!("Removed '{song_name}' from {name}");
+                self.changes.playlists.insert(name);
             } else {
                 println!("'{song_name}' does not exist in {name}");
             }
@@ -225,6 +1136,7 @@ impl MusicLibrary {
     pub fn delete_playlist(&mut self, name: String) {
         if self.playlists.remove(&name).is_some() {
             println!("Deleted playlist '{name}'");
+            self.changes.playlists.insert(name);
         } else {
             println!("Playlist '{name}' does not exist.");
         }
@@ -245,28 +1157,263 @@ fn read_metadata(path: &Path) -> Option<Song> {
 
     let properties = tagged_file.properties();
     let duration = properties.duration().as_secs();
+    let stamp = file_stamp(&path);
 
-    let tag = tagged_file.primary_tag();
+    let tag = tagged_file
+        .primary_tag()
+        .or_else(|| tagged_file.first_tag());
+    let text = |key: ItemKey| tag.and_then(|tag| tag.get_string(&key)).map(str::to_string);
     Some(Song {
-        name: tag?
-            .get_string(&ItemKey::TrackTitle)
-            .unwrap_or(&file_name)
-            .to_string(),
-        artist: tag?
-            .get_string(&ItemKey::TrackArtist)
-            .unwrap_or("Unknown")
-            .to_string(),
-        album: tag?
-            .get_string(&ItemKey::AlbumTitle)
-            .unwrap_or("Unknown")
-            .to_string(),
+        id: song_id(&path),
+        name: text(ItemKey::TrackTitle).unwrap_or(file_name),
+        artist: text(ItemKey::TrackArtist).unwrap_or_else(|| "Unknown".to_string()),
+        album: text(ItemKey::AlbumTitle).unwrap_or_else(|| "Unknown".to_string()),
         duration,
         path: file_path,
+        track_gain: tag.and_then(|tag| read_replaygain(tag, &ItemKey::ReplayGainTrackGain)),
+        album_gain: tag.and_then(|tag| read_replaygain(tag, &ItemKey::ReplayGainAlbumGain)),
+        track_peak: tag.and_then(|tag| read_replaygain(tag, &ItemKey::ReplayGainTrackPeak)),
+        album_peak: tag.and_then(|tag| read_replaygain(tag, &ItemKey::ReplayGainAlbumPeak)),
+        track: tag.and_then(|tag| tag.track()),
+        disc: tag.and_then(|tag| tag.disk()),
+        year: tag.and_then(|tag| tag.year()),
+        genre: tag
+            .and_then(|tag| tag.genre())
+            .map(|genre| genre.into_owned()),
+        album_artist: text(ItemKey::AlbumArtist),
+        composer: text(ItemKey::Composer),
+        bitrate: properties.audio_bitrate(),
+        sample_rate: properties.sample_rate(),
+        channels: properties.channels(),
+        modified: stamp.map(|(modified, _)| modified),
+        size: stamp.map(|(_, size)| size),
+        hash: file_hash(&path),
     })
 }
 
-fn is_music_file(path: &Path) -> bool {
-    let valid_exts = vec!["mp3", "flac", "wav", "ogg"];
+/// Prints how an edit would change the library and, with `write_tags`, the
+/// song's file.
+fn preview_edit(song: &Song, edited: &Song, write_tags: bool) {
+    println!("{}:", song.name);
+    let changes = tags::changes(song, edited);
+    if changes.is_empty() {
+        println!("  No changes in the library");
+    }
+    for change in changes {
+        println!("  {change}");
+    }
+    if !write_tags {
+        return;
+    }
+
+    println!("Tags in {}:", edited.path);
+    match tags::diff(edited) {
+        Ok(changes) if changes.is_empty() => println!("  No changes"),
+        Ok(changes) => {
+            for change in changes {
+                println!("  {change}");
+            }
+        }
+        Err(e) => println!("  Unable to read tags: {e}"),
+    }
+}
+
+/// Writes the song's fields to its file's tags, updating the stored stamp
+/// and hash so the next scan doesn't read the file again. Returns whether
+/// it succeeded.
+fn write_song_tags(song: &mut Song) -> bool {
+    match tags::write(song) {
+        Ok(changes) if changes.is_empty() => true,
+        Ok(changes) => {
+            refresh_stamp(song);
+            let fields: Vec<String> = changes
+                .iter()
+                .map(|change| change.field.to_string())
+                .collect();
+            println!("Updated {} in {}", fields.join(", "), song.path);
+            true
+        }
+        Err(e) => {
+            println!("Unable to write tags to {}: {e}", song.path);
+            false
+        }
+    }
+}
+
+/// Records the current modification time, size and hash of the song's file.
+fn refresh_stamp(song: &mut Song) {
+    let path = Path::new(&song.path);
+    let stamp = file_stamp(path);
+    song.modified = stamp.map(|(modified, _)| modified);
+    song.size = stamp.map(|(_, size)| size);
+    song.hash = file_hash(path);
+}
+
+fn file_hash(path: &Path) -> Option<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path).ok()?).ok()?;
+    Some(hasher.finalize().to_hex().to_string())
+}
+
+/// Whether a newly found file is the vanished song `old`, moved.
+fn same_file(old: &Song, new: &Song) -> bool {
+    match (&old.hash, &new.hash) {
+        (Some(old_hash), Some(new_hash)) => old_hash == new_hash,
+        // songs read before hashes were stored
+        _ => {
+            old.size.is_some()
+                && old.size == new.size
+                && old.duration == new.duration
+                && (&old.name, &old.artist, &old.album) == (&new.name, &new.artist, &new.album)
+        }
+    }
+}
+
+/// A file's modification time in nanoseconds and its size, used to tell
+/// whether it changed since it was last read.
+fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((modified.as_nanos() as u64, metadata.len()))
+}
+
+/// An empty value clears an optional field.
+fn optional_text(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
+}
+
+fn optional_number(value: &str) -> Result<Option<u32>, String> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("Invalid number '{value}'"))
+}
+
+/// Parses a ReplayGain tag such as `-6.53 dB`, or a peak such as `0.988`.
+fn read_replaygain(tag: &Tag, key: &ItemKey) -> Option<f32> {
+    let value = tag.get_string(key)?.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().parse().ok()
+}
+
+/// Derives a new song's ID from its canonical file path. Songs keep their ID
+/// when their file is moved.
+pub fn song_id(path: &Path) -> String {
+    let hash = blake3::hash(path.to_string_lossy().as_bytes());
+    hash.to_hex()[..16].to_string()
+}
+
+/// Picks the song named `name` out of `songs`, prompting on stdin when the
+/// name is ambiguous.
+fn choose_song<'a>(name: &str, songs: impl Iterator<Item = &'a Song>) -> Option<&'a Song> {
+    let mut matches: Vec<&Song> = songs.filter(|song| song.name == name).collect();
+    matches.sort_by(|a, b| (&a.artist, &a.album, &a.path).cmp(&(&b.artist, &b.album, &b.path)));
+    choose("song", name, matches, |song| {
+        format!(
+            "{} - {} [{}] {}",
+            song.name, song.artist, song.album, song.path
+        )
+    })
+}
+
+/// Asks a yes or no question, taking anything but yes as no.
+fn confirm(question: &str) -> bool {
+    print!("{question} [y/N] ");
+    if io::stdout().flush().is_err() {
+        return false;
+    }
+    let mut input = String::new();
+    if io::stdin().lock().read_line(&mut input).is_err() {
+        return false;
+    }
+    matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Picks one of several things sharing a name, prompting on stdin when there
+/// is more than one.
+fn choose<T>(
+    kind: &str,
+    name: &str,
+    mut matches: Vec<T>,
+    describe: impl Fn(&T) -> String,
+) -> Option<T> {
+    match matches.len() {
+        0 => None,
+        1 => matches.pop(),
+        _ => {
+            println!("Multiple {kind}s named '{name}':");
+            for (i, item) in matches.iter().enumerate() {
+                println!("  {i}) {}", describe(item));
+            }
+            let article = if kind.starts_with(['a', 'e', 'i', 'o', 'u']) {
+                "an"
+            } else {
+                "a"
+            };
+            print!("Select {article} {kind}: ");
+            io::stdout().flush().ok()?;
+
+            let mut input = String::new();
+            io::stdin().lock().read_line(&mut input).ok()?;
+            match input.trim().parse::<usize>() {
+                Ok(index) if index < matches.len() => Some(matches.swap_remove(index)),
+                _ => {
+                    println!("Invalid selection");
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// "1 song" or "N songs".
+fn count_songs(count: usize) -> String {
+    match count {
+        1 => "1 song".to_string(),
+        _ => format!("{count} songs"),
+    }
+}
+
+/// The album artist and album a song belongs to.
+fn album_key(song: &Song) -> (&str, &str) {
+    let artist = song.album_artist.as_deref().unwrap_or(&song.artist);
+    (artist, song.album.as_str())
+}
+
+/// Orders songs as they appear on their album.
+fn album_order(a: &Song, b: &Song) -> Ordering {
+    let key = |song: &Song| (song.disc.unwrap_or(1), song.track.unwrap_or(u32::MAX));
+    key(a)
+        .cmp(&key(b))
+        .then_with(|| a.name.cmp(&b.name))
+        .then_with(|| a.id.cmp(&b.id))
+}
+
+fn print_tracks(songs: &[&Song]) {
+    let discs: HashSet<u32> = songs.iter().map(|song| song.disc.unwrap_or(1)).collect();
+    for song in songs {
+        let track = match (song.track, discs.len() > 1) {
+            (Some(track), true) => format!("{}-{track:02}. ", song.disc.unwrap_or(1)),
+            (Some(track), false) => format!("{track:>2}. "),
+            (None, _) => "    ".to_string(),
+        };
+        println!(
+            "       {track}{} - {} ({})",
+            song.name,
+            song.artist,
+            duration::format(Duration::from_secs(song.duration))
+        );
+    }
+}
+
+pub fn is_music_file(path: &Path) -> bool {
+    let valid_exts = ["mp3", "flac", "wav", "ogg"];
     if let Some(ext) = path.extension() {
         if let Some(ext) = ext.to_str() {
             return valid_exts.contains(&ext);
@@ -274,3 +1421,121 @@ fn is_music_file(path: &Path) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str, path: &str) -> Song {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": id,
+            "artist": "Artist",
+            "album": "Album",
+            "duration": 60,
+            "path": path,
+        }))
+        .unwrap()
+    }
+
+    /// The files a scan of `root` with `options` would look at, relative to
+    /// the root.
+    fn walked(root: &Path, options: &ScanOptions) -> Vec<String> {
+        options
+            .walker(root)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| is_music_file(entry.path()))
+            .map(|entry| {
+                let path = entry.path().strip_prefix(root).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn walker_skips_excluded_and_ignored_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for file in [
+            "a.mp3",
+            "@eaDir/a.mp3",
+            "memos/note.mp3",
+            "album/1.mp3",
+            "album/2.flac",
+            "album/bonus/3.mp3",
+            "other/4.flac",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        // only applies inside album/
+        fs::write(root.join("album").join(MUSIC_IGNORE_FILE), "*.flac\n").unwrap();
+
+        let mut options = ScanOptions::default();
+        assert_eq!(
+            walked(root, &options),
+            [
+                "@eaDir/a.mp3",
+                "a.mp3",
+                "album/1.mp3",
+                "album/bonus/3.mp3",
+                "memos/note.mp3",
+                "other/4.flac"
+            ]
+        );
+
+        options.exclude = vec!["@eaDir".to_string(), "/memos/".to_string()];
+        assert_eq!(
+            walked(root, &options),
+            ["a.mp3", "album/1.mp3", "album/bonus/3.mp3", "other/4.flac"]
+        );
+
+        options.max_depth = Some(2);
+        assert_eq!(
+            walked(root, &options),
+            ["a.mp3", "album/1.mp3", "other/4.flac"]
+        );
+    }
+
+    #[test]
+    fn reports_invalid_exclude_patterns_as_typed() {
+        let options = ScanOptions {
+            exclude: vec!["*.wav".to_string(), "[".to_string()],
+            ..ScanOptions::default()
+        };
+        let error = options.validate().unwrap_err();
+        assert!(error.starts_with("Invalid exclude pattern '[':"), "{error}");
+    }
+
+    #[test]
+    fn free_id_avoids_ids_kept_by_moved_songs() {
+        let path = "/music/a.mp3";
+        let mut lib = MusicLibrary::new();
+        assert_eq!(lib.free_id(path), song_id(Path::new(path)));
+
+        // the song first found at `path` has since moved, keeping its ID
+        let first = song_id(Path::new(path));
+        lib.insert_song(song(&first, "/music/moved/a.mp3"));
+        let second = lib.free_id(path);
+        assert_ne!(second, first);
+        // the same library always hands out the same ID
+        assert_eq!(lib.free_id(path), second);
+
+        lib.insert_song(song(&second, "/music/moved-again/a.mp3"));
+        let third = lib.free_id(path);
+        assert!(third != first && third != second);
+    }
+
+    #[test]
+    fn parses_optional_numbers() {
+        assert_eq!(optional_number(""), Ok(None));
+        assert_eq!(optional_number("7"), Ok(Some(7)));
+        assert_eq!(optional_number("2024"), Ok(Some(2024)));
+        assert!(optional_number("-1").is_err());
+        assert!(optional_number("3/12").is_err());
+        assert!(optional_number(" 7").is_err());
+        assert_eq!(optional_text(String::new()), None);
+    }
+}
@@ -0,0 +1,69 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Writes `data` to a temporary file beside `path` and renames it into place,
+/// so readers see either the old contents or the new ones, never a partial
+/// write.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = parent.join(format!(".{file_name}.tmp-{}", process::id()));
+
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return result;
+    }
+
+    // make the rename itself durable
+    File::open(parent)?.sync_all()
+}
+
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let json_data = serde_json::to_vec_pretty(value)?;
+    write_atomic(path, &json_data)
+}
+
+/// Reads a JSON file. A file that cannot be parsed is copied aside to
+/// `<file>.corrupt-<unix time>` and reported as `InvalidData`, so callers
+/// never silently replace it.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let file = File::open(path)?;
+    match serde_json::from_reader(BufReader::new(file)) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            let backup = backup_path(path);
+            fs::copy(path, &backup)?;
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is corrupt ({e}); a copy was saved to {}",
+                    path.display(),
+                    backup.display()
+                ),
+            ))
+        }
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".corrupt-{timestamp}"));
+    PathBuf::from(backup)
+}
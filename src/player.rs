@@ -1,52 +1,353 @@
-use daemonize::Daemonize;
-use rodio::{OutputStream, Sink};
+use daemonize::{Daemonize, Outcome};
+use fastrand::Rng;
+use rodio::cpal::{
+    self,
+    traits::{DeviceTrait, HostTrait},
+};
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source, StreamError};
 use serde::{Deserialize, Serialize};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File};
 use std::io::{self, BufReader};
-use std::{process, thread, time::Duration};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::{mem, process, thread, time::Duration};
 
-use crate::config;
-use crate::music_library::Song;
+use crate::config::{self, Config, RepeatMode, ReplayGainMode};
+use crate::duration;
+use crate::ipc::{self, Request, Response, Seek, VolumeChange};
+use crate::lock::StateLock;
+use crate::music_library::{MusicLibrary, Song};
+use crate::persist;
 use crate::queue::Queue;
 
-#[derive(PartialEq, Eq, Serialize, Deserialize)]
+/// How often the daemon checks whether the current song has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Past this point `previous` restarts the current song instead
+const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+
+/// How long before the current song ends the next one is queued on the sink
+const PRELOAD_AHEAD: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PlayerState {
     Stopped,
-    Skip(Song),
     Paused(Song),
     Playing(Song),
 }
 
 impl PlayerState {
     pub fn load() -> io::Result<Self> {
-        let file_name = config::get_player_file_path();
-        let file = File::open(file_name)?;
-        let reader = BufReader::new(file);
-        let state: PlayerState = serde_json::from_reader(reader)?;
-        Ok(state)
+        persist::read_json(&config::get_player_file_path())
     }
 
     pub fn save(&self) -> io::Result<()> {
-        let file_name = config::get_player_file_path();
-        if let Some(parent) = file_name.parent() {
-            std::fs::create_dir_all(parent)?;
+        persist::write_json(&config::get_player_file_path(), self)
+    }
+}
+
+/// Player preferences: the defaults, overridden by the player section of the
+/// config file, overridden in turn by what was set with the player commands.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlayerSettings {
+    /// Volume in percent
+    pub volume: u8,
+    pub muted: bool,
+    pub repeat: RepeatMode,
+    /// Play queued songs in random order
+    pub shuffle: bool,
+    /// Seed for the shuffle order, so it can be reproduced
+    pub shuffle_seed: Option<u64>,
+    /// How long consecutive songs overlap, zero for gapless playback
+    pub crossfade: Duration,
+    pub replaygain: ReplayGainMode,
+}
+
+impl Default for PlayerSettings {
+    fn default() -> Self {
+        PlayerSettings {
+            volume: 100,
+            muted: false,
+            repeat: RepeatMode::Off,
+            shuffle: false,
+            shuffle_seed: None,
+            crossfade: Duration::ZERO,
+            replaygain: ReplayGainMode::Off,
+        }
+    }
+}
+
+/// The settings changed with the player commands, saved beside the player
+/// state. Whatever was never changed keeps following the config file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SettingOverrides {
+    volume: Option<u8>,
+    muted: Option<bool>,
+    repeat: Option<RepeatMode>,
+    shuffle: Option<bool>,
+    shuffle_seed: Option<u64>,
+    crossfade: Option<Duration>,
+    replaygain: Option<ReplayGainMode>,
+}
+
+impl SettingOverrides {
+    /// Loads the saved overrides, falling back to none when there are none or
+    /// they cannot be read.
+    fn load() -> Self {
+        match persist::read_json(&config::get_settings_file_path()) {
+            Ok(overrides) => overrides,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => SettingOverrides::default(),
+            Err(e) => {
+                eprintln!("Error loading player settings: {e}");
+                SettingOverrides::default()
+            }
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        persist::write_json(&config::get_settings_file_path(), self)
+    }
+
+    fn apply(&self, settings: &mut PlayerSettings) {
+        if let Some(volume) = self.volume {
+            settings.volume = volume.min(100);
+        }
+        if let Some(muted) = self.muted {
+            settings.muted = muted;
+        }
+        if let Some(repeat) = self.repeat {
+            settings.repeat = repeat;
+        }
+        if let Some(shuffle) = self.shuffle {
+            settings.shuffle = shuffle;
+            settings.shuffle_seed = self.shuffle_seed;
+        }
+        if let Some(crossfade) = self.crossfade {
+            settings.crossfade = crossfade;
+        }
+        if let Some(replaygain) = self.replaygain {
+            settings.replaygain = replaygain;
+        }
+    }
+
+    /// Records the fields that differ between `before` and `after` as
+    /// overridden.
+    fn record(&mut self, before: &PlayerSettings, after: &PlayerSettings) {
+        if after.volume != before.volume {
+            self.volume = Some(after.volume);
+        }
+        if after.muted != before.muted {
+            self.muted = Some(after.muted);
+        }
+        if after.repeat != before.repeat {
+            self.repeat = Some(after.repeat);
+        }
+        if after.shuffle != before.shuffle || after.shuffle_seed != before.shuffle_seed {
+            self.shuffle = Some(after.shuffle);
+            self.shuffle_seed = after.shuffle_seed;
+        }
+        if after.crossfade != before.crossfade {
+            self.crossfade = Some(after.crossfade);
+        }
+        if after.replaygain != before.replaygain {
+            self.replaygain = Some(after.replaygain);
+        }
+    }
+}
+
+impl PlayerSettings {
+    /// The current settings: the configured ones with the saved overrides
+    /// applied.
+    pub fn load() -> Self {
+        let mut settings = PlayerSettings::configured();
+        SettingOverrides::load().apply(&mut settings);
+        settings
+    }
+
+    /// The defaults, overridden by the player section of the config file.
+    fn configured() -> Self {
+        let mut settings = PlayerSettings::default();
+        let player = match Config::load() {
+            Ok(config) => config.player,
+            Err(e) => {
+                eprintln!("Error loading config: {e}");
+                return settings;
+            }
+        };
+        if let Some(volume) = player.volume {
+            settings.volume = volume.min(100);
+        }
+        if let Some(repeat) = player.repeat {
+            settings.repeat = repeat;
+        }
+        if let Some(shuffle) = player.shuffle {
+            settings.shuffle = shuffle;
+        }
+        if let Some(crossfade) = player.crossfade {
+            settings.crossfade = crossfade;
+        }
+        if let Some(replaygain) = player.replaygain {
+            settings.replaygain = replaygain;
+        }
+        settings
+    }
+
+    /// Loads the settings, applies `f` and saves what it changed as
+    /// overrides while holding the state lock.
+    fn update(f: impl FnOnce(&mut PlayerSettings)) -> io::Result<PlayerSettings> {
+        let _lock = StateLock::acquire()?;
+        let mut overrides = SettingOverrides::load();
+        let mut settings = PlayerSettings::configured();
+        overrides.apply(&mut settings);
+        let before = settings.clone();
+        f(&mut settings);
+        overrides.record(&before, &settings);
+        overrides.save()?;
+        Ok(settings)
+    }
+
+    fn change_volume(&mut self, change: VolumeChange) {
+        self.volume = match change {
+            VolumeChange::Set(volume) => volume,
+            VolumeChange::Up(step) => self.volume.saturating_add(step),
+            VolumeChange::Down(step) => self.volume.saturating_sub(step),
+        }
+        .min(100);
+    }
+
+    fn set_shuffle(&mut self, enabled: bool, seed: Option<u64>) {
+        self.shuffle = enabled;
+        self.shuffle_seed = seed;
+    }
+
+    /// The factor to scale samples by.
+    fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            f32::from(self.volume) / 100.0
         }
+    }
+
+    /// The factor to scale a song's samples by, including its ReplayGain
+    /// adjustment. Album mode falls back to the track gain and vice versa.
+    /// The adjustment never lifts the peak past full scale, and without a
+    /// known peak it only makes songs quieter.
+    fn song_gain(&self, song: &Song) -> f32 {
+        let track = song.track_gain.map(|gain| (gain, song.track_peak));
+        let album = song.album_gain.map(|gain| (gain, song.album_peak));
+        let adjustment = match self.replaygain {
+            ReplayGainMode::Off => None,
+            ReplayGainMode::Track => track.or(album),
+            ReplayGainMode::Album => album.or(track),
+        };
+        let factor = match adjustment {
+            None => 1.0,
+            Some((db, peak)) => {
+                let limit = match peak {
+                    Some(peak) if peak > 0.0 => 1.0 / peak,
+                    _ => 1.0,
+                };
+                10f32.powf(db / 20.0).min(limit)
+            }
+        };
+        self.gain() * factor
+    }
+
+    fn describe_volume(&self) -> String {
+        if self.muted {
+            format!("Volume: {}% (muted)", self.volume)
+        } else {
+            format!("Volume: {}%", self.volume)
+        }
+    }
+
+    fn describe_repeat(&self) -> String {
+        format!("Repeat: {}", self.repeat)
+    }
+
+    fn describe_shuffle(&self) -> String {
+        match (self.shuffle, self.shuffle_seed) {
+            (true, Some(seed)) => format!("Shuffle: on (seed {seed})"),
+            (true, None) => "Shuffle: on".to_string(),
+            (false, _) => "Shuffle: off".to_string(),
+        }
+    }
+
+    fn describe_replaygain(&self) -> String {
+        format!("ReplayGain: {}", self.replaygain)
+    }
+
+    fn describe_crossfade(&self) -> String {
+        if self.crossfade.is_zero() {
+            "Crossfade: off".to_string()
+        } else {
+            format!("Crossfade: {}s", self.crossfade.as_secs_f64())
+        }
+    }
+
+    fn rng(&self) -> Rng {
+        match self.shuffle_seed {
+            Some(seed) => Rng::with_seed(seed),
+            None => Rng::new(),
+        }
+    }
 
-        let file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(file_name)?;
-        serde_json::to_writer_pretty(file, self)?;
-        Ok(())
+    fn print(&self) {
+        println!("{}", self.describe_volume());
+        println!("{}", self.describe_repeat());
+        println!("{}", self.describe_shuffle());
+        println!("{}", self.describe_crossfade());
+        println!("{}", self.describe_replaygain());
+    }
+}
+
+/// What the daemon reports for `Request::Status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub state: PlayerState,
+    /// Elapsed time in the current song
+    pub position: Duration,
+    pub settings: PlayerSettings,
+}
+
+impl Status {
+    fn print(&self) {
+        let progress = |song: &Song| {
+            format!(
+                "{} / {}",
+                duration::format(self.position),
+                duration::format(Duration::from_secs(song.duration))
+            )
+        };
+        match &self.state {
+            PlayerState::Playing(song) => println!(
+                "Playing: {} by {} ({})",
+                song.name,
+                song.artist,
+                progress(song)
+            ),
+            PlayerState::Paused(song) => println!(
+                "Paused: {} by {} ({})",
+                song.name,
+                song.artist,
+                progress(song)
+            ),
+            PlayerState::Stopped => println!("No songs playing"),
+        }
+        self.settings.print();
     }
 }
 
 struct Playing {
     song: Song,
+    status: Status,
 }
 struct Paused {
     song: Song,
+    status: Status,
 }
 struct Stopped;
 
@@ -55,8 +356,16 @@ pub trait PlayerAction {
     fn pause(&self);
     fn resume(&self);
     fn skip(&self);
+    fn previous(&self);
     fn stop(&self);
     fn status(&self);
+    fn seek(&self, position: Seek);
+    fn volume(&self, change: VolumeChange);
+    fn mute(&self, muted: bool);
+    fn repeat(&self, mode: RepeatMode);
+    fn shuffle(&self, enabled: bool, seed: Option<u64>);
+    fn crossfade(&self, length: Duration);
+    fn replaygain(&self, mode: ReplayGainMode);
 }
 
 impl PlayerAction for Playing {
@@ -67,7 +376,7 @@ impl PlayerAction for Playing {
         );
     }
     fn pause(&self) {
-        let _ = PlayerState::Paused(self.song.clone()).save();
+        request(Request::Pause);
     }
     fn resume(&self) {
         println!(
@@ -76,13 +385,41 @@ impl PlayerAction for Playing {
         );
     }
     fn stop(&self) {
-        let _ = PlayerState::Stopped.save();
+        request(Request::Stop);
     }
     fn skip(&self) {
-        let _ = PlayerState::Skip(self.song.clone()).save();
+        request(Request::Skip);
+    }
+    fn previous(&self) {
+        request(Request::Previous);
     }
     fn status(&self) {
-        println!("Playing: {} by {}", self.song.name, self.song.artist);
+        self.status.print();
+    }
+    fn seek(&self, position: Seek) {
+        request(Request::Seek(position));
+    }
+    fn volume(&self, change: VolumeChange) {
+        request(Request::Volume(change));
+    }
+    fn mute(&self, muted: bool) {
+        request(if muted {
+            Request::Mute
+        } else {
+            Request::Unmute
+        });
+    }
+    fn repeat(&self, mode: RepeatMode) {
+        request(Request::Repeat(mode));
+    }
+    fn shuffle(&self, enabled: bool, seed: Option<u64>) {
+        request(Request::Shuffle { enabled, seed });
+    }
+    fn crossfade(&self, length: Duration) {
+        request(Request::Crossfade(length));
+    }
+    fn replaygain(&self, mode: ReplayGainMode) {
+        request(Request::ReplayGain(mode));
     }
 }
 
@@ -97,27 +434,52 @@ impl PlayerAction for Paused {
         );
     }
     fn resume(&self) {
-        let _ = PlayerState::Playing(self.song.clone()).save();
+        request(Request::Resume);
     }
     fn stop(&self) {
-        let _ = PlayerState::Stopped.save();
+        request(Request::Stop);
     }
     fn skip(&self) {
-        let _ = PlayerState::Skip(self.song.clone()).save();
+        request(Request::Skip);
+    }
+    fn previous(&self) {
+        request(Request::Previous);
     }
     fn status(&self) {
-        println!("Paused: {} by {}", self.song.name, self.song.artist);
+        self.status.print();
+    }
+    fn seek(&self, position: Seek) {
+        request(Request::Seek(position));
+    }
+    fn volume(&self, change: VolumeChange) {
+        request(Request::Volume(change));
+    }
+    fn mute(&self, muted: bool) {
+        request(if muted {
+            Request::Mute
+        } else {
+            Request::Unmute
+        });
+    }
+    fn repeat(&self, mode: RepeatMode) {
+        request(Request::Repeat(mode));
+    }
+    fn shuffle(&self, enabled: bool, seed: Option<u64>) {
+        request(Request::Shuffle { enabled, seed });
+    }
+    fn crossfade(&self, length: Duration) {
+        request(Request::Crossfade(length));
+    }
+    fn replaygain(&self, mode: ReplayGainMode) {
+        request(Request::ReplayGain(mode));
     }
 }
 
 impl PlayerAction for Stopped {
     fn play(&self) {
         match Queue::load() {
-            Ok(queue) => match queue.peek() {
-                Some(song) => {
-                    let _ = PlayerState::Playing(song.clone()).save();
-                    create_daemon();
-                }
+            Ok(queue) => match queue.peek().and_then(|id| library_song(id)) {
+                Some(_) => create_daemon(),
                 None => println!("Queue is empty!"),
             },
             Err(_) => println!("Error loading queue"),
@@ -135,145 +497,797 @@ impl PlayerAction for Stopped {
     fn skip(&self) {
         println!("No song playing");
     }
+    fn previous(&self) {
+        println!("No song playing");
+    }
     fn status(&self) {
         println!("No songs playing");
+        PlayerSettings::load().print();
+    }
+    fn seek(&self, _position: Seek) {
+        println!("No song playing");
+    }
+    fn volume(&self, change: VolumeChange) {
+        match PlayerSettings::update(|settings| settings.change_volume(change)) {
+            Ok(settings) => println!("{}", settings.describe_volume()),
+            Err(e) => println!("Error saving player settings: {e}"),
+        }
+    }
+    fn mute(&self, muted: bool) {
+        match PlayerSettings::update(|settings| settings.muted = muted) {
+            Ok(settings) => println!("{}", settings.describe_volume()),
+            Err(e) => println!("Error saving player settings: {e}"),
+        }
+    }
+    fn repeat(&self, mode: RepeatMode) {
+        match PlayerSettings::update(|settings| settings.repeat = mode) {
+            Ok(settings) => println!("{}", settings.describe_repeat()),
+            Err(e) => println!("Error saving player settings: {e}"),
+        }
+    }
+    fn shuffle(&self, enabled: bool, seed: Option<u64>) {
+        match PlayerSettings::update(|settings| settings.set_shuffle(enabled, seed)) {
+            Ok(settings) => println!("{}", settings.describe_shuffle()),
+            Err(e) => println!("Error saving player settings: {e}"),
+        }
+    }
+    fn crossfade(&self, length: Duration) {
+        match PlayerSettings::update(|settings| settings.crossfade = length) {
+            Ok(settings) => println!("{}", settings.describe_crossfade()),
+            Err(e) => println!("Error saving player settings: {e}"),
+        }
+    }
+    fn replaygain(&self, mode: ReplayGainMode) {
+        match PlayerSettings::update(|settings| settings.replaygain = mode) {
+            Ok(settings) => println!("{}", settings.describe_replaygain()),
+            Err(e) => println!("Error saving player settings: {e}"),
+        }
+    }
+}
+
+/// Sends a request to the player daemon and prints its reply.
+fn request(request: Request) {
+    match ipc::send(&request) {
+        Ok(Response::Ok(message)) | Ok(Response::Error(message)) => println!("{message}"),
+        Ok(Response::Status(status)) => status.print(),
+        Err(_) => println!("Player daemon is not responding"),
     }
 }
 
 pub struct Player;
 
 impl Player {
+    /// Asks the daemon what it is doing. When no daemon answers the player is
+    /// stopped, and state left behind by a daemon that died is cleared.
     pub fn load() -> Box<dyn PlayerAction> {
-        let player: Box<dyn PlayerAction> = match PlayerState::load() {
-            Ok(state) => match state {
-                PlayerState::Playing(song) => Box::new(Playing { song }),
-                PlayerState::Paused(song) => Box::new(Paused { song }),
-                PlayerState::Skip(song) => Box::new(Playing { song }),
-                PlayerState::Stopped => Box::new(Stopped),
-            },
+        match ipc::send(&Request::Status) {
+            Ok(Response::Status(status)) => {
+                let status = *status;
+                match status.state.clone() {
+                    PlayerState::Playing(song) => Box::new(Playing { song, status }),
+                    PlayerState::Paused(song) => Box::new(Paused { song, status }),
+                    PlayerState::Stopped => Box::new(Stopped),
+                }
+            }
+            Ok(_) => Box::new(Stopped),
             Err(_) => {
-                let _ = PlayerState::Stopped.save();
+                let _lock = StateLock::acquire();
+                let reset = match PlayerState::load() {
+                    Ok(PlayerState::Stopped) => false,
+                    Ok(_) => true,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+                    // the message says where the unreadable file was copied
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        eprintln!("Error loading player state: {e}");
+                        true
+                    }
+                    // left alone, as it may not have been backed up
+                    Err(e) => {
+                        eprintln!("Error loading player state: {e}");
+                        false
+                    }
+                };
+                if reset {
+                    if let Err(e) = PlayerState::Stopped.save() {
+                        eprintln!("Failed to save player state: {e}");
+                    }
+                }
                 Box::new(Stopped)
             }
-        };
-        player
+        }
     }
 }
 
-fn create_daemon() {
-    let stdout = File::create("/tmp/music-lib-player.out").unwrap();
-    let stderr = File::create("/tmp/music-lib-player.err").unwrap();
+fn library_song(id: &str) -> Option<Song> {
+    MusicLibrary::load_song(id).ok()?
+}
 
-    let daemonize = Daemonize::new()
-        .pid_file("/tmp/music-lib-player.pid")
-        .chown_pid_file(true)
-        .working_directory("/tmp")
-        .stdout(stdout)
-        .stderr(stderr)
-        .privileged_action(move || {
-            let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-            let sink = Sink::try_new(&stream_handle).unwrap();
-
-            loop {
-                let state = match PlayerState::load() {
-                    Ok(state) => state,
-                    Err(_) => {
-                        eprintln!("Error loading player state");
-                        process::exit(1);
-                    }
-                };
+/// Picks the next song in the queue that can be decoded, at random when given
+/// an `rng`, dropping any that can't. The song is left on the queue.
+fn next_playable(
+    queue: &mut Queue,
+    mut rng: Option<&mut Rng>,
+) -> Option<(Song, Decoder<BufReader<File>>)> {
+    // songs are looked up one at a time so the queue isn't locked while the
+    // whole library loads
+    while let Some(song) = queue
+        .next_song(MusicLibrary::load_song, rng.as_deref_mut())
+        .ok()?
+    {
+        match song.get_source() {
+            Some(source) => return Some((song, source)),
+            None => {
+                eprintln!("Unable to play '{}', skipping", song.path);
+                queue.take(&song.id);
+            }
+        }
+    }
+    None
+}
 
-                match state {
-                    PlayerState::Playing(_) => {
-                        if sink.is_paused() {
-                            // resume if simk is paused
-                            sink.play();
-                        } else if sink.empty() {
-                            // if sink is empty, play next song in queue
-                            match Queue::load() {
-                                Ok(mut queue) => match queue.pop() {
-                                    Some(song) => match song.get_source() {
-                                        Some(source) => {
-                                            sink.append(source);
-                                            if let Ok(_) = queue.save() {
-                                                let _ = PlayerState::Playing(song).save();
-                                            } else {
-                                                eprintln!("Failed to update queue");
-                                            }
-                                        }
-                                        None => {
-                                            // unable to play song
-                                            let _ = PlayerState::Skip(song).save();
-                                        }
-                                    },
-                                    None => {
-                                        // Queue is empty
-                                        sink.stop();
-                                        let _ = PlayerState::Stopped.save();
-                                        break;
-                                    }
-                                },
-                                Err(_) => {
-                                    eprintln!("Failed to load queue.");
-                                    let _ = PlayerState::Stopped.save();
-                                    process::exit(1);
-                                }
-                            }
-                        }
+/// A song appended to the sink behind the current one, so that it starts
+/// without a gap.
+struct Upcoming {
+    song: Song,
+    /// Whether it was picked from the queue rather than repeated
+    queued: bool,
+}
+
+/// The previous song's sink while it fades out under the current one.
+struct Fading {
+    sink: Sink,
+    volume: f32,
+    length: Duration,
+    crossfade: Duration,
+}
+
+struct Daemon {
+    stream: OutputStreamHandle,
+    sink: Sink,
+    state: PlayerState,
+    settings: PlayerSettings,
+    /// Picks the next song while shuffling
+    rng: Rng,
+    upcoming: Option<Upcoming>,
+    /// Whether the song after the current one has been looked for yet
+    looked_ahead: bool,
+    fading: Option<Fading>,
+}
+
+impl Daemon {
+    /// Starts the next playable song in the queue, stopping once it is empty.
+    fn play_next(&mut self) {
+        self.start_next(self.settings.shuffle);
+    }
+
+    fn start_next(&mut self, shuffle: bool) {
+        self.sink.clear();
+        self.upcoming = None;
+        self.fading = None;
+
+        let rng = shuffle.then_some(&mut self.rng);
+        let next = Queue::update(|queue| {
+            let next = next_playable(queue, rng);
+            if let Some((song, _)) = &next {
+                queue.take(&song.id);
+                queue.record(&song.id);
+            }
+            next
+        });
+
+        match next {
+            Ok(Some((song, source))) => {
+                self.sink.append(source);
+                self.sink.play();
+                self.begin(song, false);
+            }
+            Ok(None) => {
+                self.sink.stop();
+                self.set_state(PlayerState::Stopped);
+            }
+            Err(e) => {
+                eprintln!("Failed to update queue: {e}");
+                self.sink.stop();
+                self.set_state(PlayerState::Stopped);
+            }
+        }
+    }
+
+    /// Picks the song to follow `current`, honouring the repeat and shuffle
+    /// modes. Returns whether it came from the queue.
+    fn pick_next(&mut self, current: &Song) -> Option<(Song, Decoder<BufReader<File>>, bool)> {
+        if self.settings.repeat == RepeatMode::One {
+            if let Some(source) = current.get_source() {
+                return Some((current.clone(), source, false));
+            }
+        }
+
+        let rng = self.settings.shuffle.then_some(&mut self.rng);
+        match Queue::update(|queue| next_playable(queue, rng)) {
+            Ok(next) => next.map(|(song, source)| (song, source, true)),
+            Err(e) => {
+                eprintln!("Failed to update queue: {e}");
+                None
+            }
+        }
+    }
+
+    /// Marks `song` as the one playing, taking it off the queue if it was
+    /// picked from there.
+    fn begin(&mut self, song: Song, queued: bool) {
+        if queued {
+            let result = Queue::update(|queue| {
+                queue.take(&song.id);
+                queue.record(&song.id);
+            });
+            if let Err(e) = result {
+                eprintln!("Failed to update queue: {e}");
+            }
+        }
+        self.looked_ahead = false;
+        self.set_state(PlayerState::Playing(song));
+        self.apply_volume();
+    }
+
+    fn apply_volume(&self) {
+        let gain = match &self.state {
+            PlayerState::Playing(song) | PlayerState::Paused(song) => self.settings.song_gain(song),
+            PlayerState::Stopped => self.settings.gain(),
+        };
+        self.sink.set_volume(gain);
+    }
+
+    /// Appends the next song behind the current one for gapless playback.
+    fn preload(&mut self, current: &Song) {
+        self.looked_ahead = true;
+        if let Some((song, source, queued)) = self.pick_next(current) {
+            self.sink.append(source);
+            self.upcoming = Some(Upcoming { song, queued });
+        }
+    }
+
+    /// Starts the next song on a fresh sink, fading it in while the current
+    /// one fades out.
+    fn crossfade(&mut self, current: &Song, fade: Duration) {
+        self.looked_ahead = true;
+        let Some((song, source, queued)) = self.pick_next(current) else {
+            return;
+        };
+        let sink = match Sink::try_new(&self.stream) {
+            Ok(sink) => sink,
+            Err(e) => {
+                eprintln!("Unable to create audio sink: {e}");
+                return;
+            }
+        };
+        sink.set_volume(self.settings.song_gain(&song));
+        sink.append(source.fade_in(fade));
+        self.requeue(current);
+
+        self.fading = Some(Fading {
+            sink: mem::replace(&mut self.sink, sink),
+            volume: self.settings.song_gain(current),
+            length: Duration::from_secs(current.duration),
+            crossfade: fade,
+        });
+        self.begin(song, queued);
+    }
+
+    /// Moves on once the current song has finished or is about to.
+    fn tick(&mut self) {
+        if let Some(fading) = &self.fading {
+            if fading.sink.empty() {
+                self.fading = None;
+            } else {
+                let left = fading.length.saturating_sub(fading.sink.get_pos());
+                let fraction = left.as_secs_f32() / fading.crossfade.as_secs_f32();
+                fading.sink.set_volume(fading.volume * fraction.min(1.0));
+            }
+        }
+
+        let PlayerState::Playing(song) = self.state.clone() else {
+            return;
+        };
+        if self.upcoming.is_some() {
+            // the current song has finished once only the upcoming one is left
+            if self.sink.len() <= 1 {
+                if let Some(upcoming) = self.upcoming.take() {
+                    self.requeue(&song);
+                    self.begin(upcoming.song, upcoming.queued);
+                }
+            }
+            return;
+        }
+        if self.sink.empty() {
+            self.advance(song);
+            return;
+        }
+        if self.looked_ahead {
+            return;
+        }
+
+        let length = Duration::from_secs(song.duration);
+        let remaining = length.saturating_sub(self.sink.get_pos());
+        let crossfade = self.settings.crossfade;
+        if !crossfade.is_zero() && !length.is_zero() {
+            // short songs fade over at most their second half, so they are
+            // still heard and don't fade straight into one another
+            let fade = crossfade.min(length / 2);
+            if remaining <= fade {
+                self.crossfade(&song, fade);
+            }
+        } else if remaining <= PRELOAD_AHEAD {
+            self.preload(&song);
+        }
+    }
+
+    /// Goes back to the previous song in the history, or restarts the
+    /// current one when it is already underway or there is none.
+    fn previous(&mut self, song: Song) -> Response {
+        let repeat_all = self.settings.repeat == RepeatMode::All;
+        let rewound = self.sink.get_pos() <= RESTART_THRESHOLD
+            && match Queue::update(|queue| queue.rewind(repeat_all)) {
+                Ok(rewound) => rewound,
+                Err(e) => return Response::Error(format!("Failed to update queue: {e}")),
+            };
+
+        if rewound {
+            // the previous song is at the front, even when shuffling
+            self.start_next(false);
+            match &self.state {
+                PlayerState::Playing(song) | PlayerState::Paused(song) => {
+                    Response::Ok(format!("Playing {} by {}", song.name, song.artist))
+                }
+                PlayerState::Stopped => Response::Ok("Queue is empty!".to_string()),
+            }
+        } else {
+            match self.sink.try_seek(Duration::ZERO) {
+                Ok(()) => Response::Ok(format!("Restarted {} by {}", song.name, song.artist)),
+                Err(e) => Response::Error(format!("Unable to restart song: {e}")),
+            }
+        }
+    }
+
+    fn set_state(&mut self, state: PlayerState) {
+        let _lock = StateLock::acquire();
+        if let Err(e) = state.save() {
+            eprintln!("Failed to save player state: {e}");
+        }
+        self.state = state;
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            state: self.state.clone(),
+            position: self.sink.get_pos(),
+            settings: self.settings.clone(),
+        }
+    }
+
+    /// Updates and applies the settings, replying with `describe`.
+    fn update_settings(
+        &mut self,
+        f: impl FnOnce(&mut PlayerSettings),
+        describe: fn(&PlayerSettings) -> String,
+    ) -> Response {
+        match PlayerSettings::update(f) {
+            Ok(settings) => {
+                let response = Response::Ok(describe(&settings));
+                self.settings = settings;
+                self.apply_volume();
+                response
+            }
+            Err(e) => Response::Error(format!("Error saving player settings: {e}")),
+        }
+    }
+
+    /// Puts a finished or skipped song back at the end of the queue when
+    /// repeating the whole queue.
+    fn requeue(&self, song: &Song) {
+        if self.settings.repeat != RepeatMode::All {
+            return;
+        }
+        if let Err(e) = Queue::update(|queue| queue.songs.push_back(song.id.clone())) {
+            eprintln!("Failed to update queue: {e}");
+        }
+    }
+
+    /// Moves on once the current song has finished without the next one
+    /// having been lined up.
+    fn advance(&mut self, song: Song) {
+        self.requeue(&song);
+        match self.pick_next(&song) {
+            Some((next, source, queued)) => {
+                self.sink.append(source);
+                self.begin(next, queued);
+            }
+            None => {
+                self.sink.stop();
+                self.set_state(PlayerState::Stopped);
+            }
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        match request {
+            Request::Play | Request::Resume => match self.state.clone() {
+                PlayerState::Paused(song) => {
+                    self.sink.play();
+                    if let Some(fading) = &self.fading {
+                        fading.sink.play();
                     }
-                    PlayerState::Paused(_) => {
-                        if !sink.is_paused() {
-                            sink.pause();
+                    self.set_state(PlayerState::Playing(song.clone()));
+                    Response::Ok(format!("Resumed {} by {}", song.name, song.artist))
+                }
+                PlayerState::Playing(song) => Response::Ok(format!(
+                    "{} by {} is already playing",
+                    song.name, song.artist
+                )),
+                PlayerState::Stopped => Response::Error("No song playing".to_string()),
+            },
+            Request::Pause => match self.state.clone() {
+                PlayerState::Playing(song) => {
+                    self.sink.pause();
+                    if let Some(fading) = &self.fading {
+                        fading.sink.pause();
+                    }
+                    self.set_state(PlayerState::Paused(song.clone()));
+                    Response::Ok(format!("Paused {} by {}", song.name, song.artist))
+                }
+                PlayerState::Paused(song) => Response::Ok(format!(
+                    "{} by {} is already paused",
+                    song.name, song.artist
+                )),
+                PlayerState::Stopped => Response::Error("No song playing".to_string()),
+            },
+            Request::Skip => {
+                match self.upcoming.take() {
+                    Some(upcoming) if upcoming.queued => {
+                        if let PlayerState::Playing(song) | PlayerState::Paused(song) = &self.state
+                        {
+                            self.requeue(song);
                         }
+                        self.sink.skip_one();
+                        self.sink.play();
+                        self.begin(upcoming.song, true);
                     }
-                    PlayerState::Skip(_) => {
-                        sink.stop();
-                        // play next song in queue
-                        match Queue::load() {
-                            Ok(mut queue) => match queue.pop() {
-                                Some(song) => {
-                                    if let Some(source) = song.get_source() {
-                                        sink.append(source);
-                                        if let Ok(_) = queue.save() {
-                                            let _ = PlayerState::Playing(song).save();
-                                        } else {
-                                            eprintln!("Failed to update queue");
-                                        }
-                                    } else {
-                                        // if we are unable to get source,
-                                        // save queue and keep skipping
-                                        if let Ok(_) = queue.save() {
-                                            let _ = PlayerState::Skip(song).save();
-                                        } else {
-                                            eprintln!("Failed to update queue");
-                                        }
-                                    }
-                                }
-                                None => {
-                                    // queue is empty
-                                    let _ = PlayerState::Stopped.save();
-                                }
-                            },
-                            Err(_) => {
-                                eprintln!("Failed to load queue.");
-                                let _ = PlayerState::Stopped.save();
-                                process::exit(1);
-                            }
+                    _ => {
+                        if let PlayerState::Playing(song) | PlayerState::Paused(song) = &self.state
+                        {
+                            self.requeue(song);
                         }
+                        self.play_next();
+                    }
+                }
+                match &self.state {
+                    PlayerState::Playing(song) | PlayerState::Paused(song) => {
+                        Response::Ok(format!("Playing {} by {}", song.name, song.artist))
                     }
+                    PlayerState::Stopped => Response::Ok("Queue is empty!".to_string()),
+                }
+            }
+            Request::Previous => match self.state.clone() {
+                PlayerState::Playing(song) | PlayerState::Paused(song) => self.previous(song),
+                PlayerState::Stopped => Response::Error("No song playing".to_string()),
+            },
+            Request::Stop => {
+                self.sink.stop();
+                self.fading = None;
+                self.set_state(PlayerState::Stopped);
+                Response::Ok("Stopped playback".to_string())
+            }
+            Request::Status => Response::Status(Box::new(self.status())),
+            Request::Seek(seek) => {
+                let song = match &self.state {
+                    PlayerState::Playing(song) | PlayerState::Paused(song) => song,
                     PlayerState::Stopped => {
-                        if !sink.empty() {
-                            sink.stop();
-                            break;
-                        }
+                        return Response::Error("No song playing".to_string());
                     }
+                };
+                let length = Duration::from_secs(song.duration);
+                let position = self.sink.get_pos();
+                let target = match seek {
+                    Seek::To(target) => target,
+                    Seek::Forward(offset) => position.saturating_add(offset),
+                    Seek::Back(offset) => position.saturating_sub(offset),
+                }
+                .min(length);
+
+                match self.sink.try_seek(target) {
+                    Ok(()) => Response::Ok(format!(
+                        "Seeked to {} / {}",
+                        duration::format(target),
+                        duration::format(length)
+                    )),
+                    Err(e) => Response::Error(format!("Unable to seek: {e}")),
                 }
-                thread::sleep(Duration::from_secs(1));
             }
+            Request::Volume(change) => self.update_settings(
+                |settings| settings.change_volume(change),
+                PlayerSettings::describe_volume,
+            ),
+            Request::Mute => self.update_settings(
+                |settings| settings.muted = true,
+                PlayerSettings::describe_volume,
+            ),
+            Request::Unmute => self.update_settings(
+                |settings| settings.muted = false,
+                PlayerSettings::describe_volume,
+            ),
+            Request::Repeat(mode) => self.update_settings(
+                |settings| settings.repeat = mode,
+                PlayerSettings::describe_repeat,
+            ),
+            Request::Crossfade(length) => self.update_settings(
+                |settings| settings.crossfade = length,
+                PlayerSettings::describe_crossfade,
+            ),
+            Request::ReplayGain(mode) => self.update_settings(
+                |settings| settings.replaygain = mode,
+                PlayerSettings::describe_replaygain,
+            ),
+            Request::Shuffle { enabled, seed } => {
+                let response = self.update_settings(
+                    |settings| settings.set_shuffle(enabled, seed),
+                    PlayerSettings::describe_shuffle,
+                );
+                self.rng = self.settings.rng();
+                response
+            }
+        }
+    }
+}
+
+/// Accepts connections on the control socket, passing each request and the
+/// connection to answer it on to the daemon loop.
+fn listen() -> io::Result<Receiver<(Request, UnixStream)>> {
+    let socket_path = config::get_socket_path();
+    // left behind by a daemon that did not shut down cleanly
+    let _ = fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path)?;
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            match ipc::read_message(&mut BufReader::new(&stream)) {
+                Ok(request) => {
+                    if sender.send((request, stream)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let response = Response::Error(format!("Invalid request: {e}"));
+                    let _ = ipc::write_message(&stream, &response);
+                }
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+/// Opens the output device named in the config file, or the default one.
+fn open_output() -> Result<(OutputStream, OutputStreamHandle), StreamError> {
+    let name = match Config::load() {
+        Ok(config) => config.output.device,
+        Err(e) => {
+            eprintln!("Error loading config: {e}");
+            None
+        }
+    };
+    let Some(name) = name else {
+        return OutputStream::try_default();
+    };
+
+    match cpal::default_host().output_devices() {
+        Ok(devices) => {
+            for device in devices {
+                if device.name().is_ok_and(|device_name| device_name == name) {
+                    return OutputStream::try_from_device(&device);
+                }
+            }
+            eprintln!("Audio device '{name}' not found, using the default one");
+        }
+        Err(e) => eprintln!("Unable to list audio devices: {e}"),
+    }
+    OutputStream::try_default()
+}
+
+fn run_daemon() {
+    let (_stream, stream_handle) = match open_output() {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Unable to open audio output: {e}");
+            return;
+        }
+    };
+    let sink = match Sink::try_new(&stream_handle) {
+        Ok(sink) => sink,
+        Err(e) => {
+            eprintln!("Unable to create audio sink: {e}");
+            return;
+        }
+    };
+
+    let settings = PlayerSettings::load();
+    sink.set_volume(settings.gain());
+
+    let mut daemon = Daemon {
+        stream: stream_handle,
+        sink,
+        state: PlayerState::Stopped,
+        rng: settings.rng(),
+        settings,
+        upcoming: None,
+        looked_ahead: false,
+        fading: None,
+    };
+    daemon.play_next();
+
+    let requests = match listen() {
+        Ok(requests) => requests,
+        Err(e) => {
+            eprintln!("Unable to open control socket: {e}");
+            daemon.set_state(PlayerState::Stopped);
+            return;
+        }
+    };
+
+    while daemon.state != PlayerState::Stopped {
+        match requests.recv_timeout(POLL_INTERVAL) {
+            Ok((request, stream)) => {
+                let response = daemon.handle(request);
+                let _ = ipc::write_message(&stream, &response);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        daemon.tick();
+    }
+    let _ = fs::remove_file(config::get_socket_path());
+}
+
+/// Creates the daemon's log files, and the directories for them and its pid
+/// file.
+fn open_logs() -> io::Result<(File, File)> {
+    fs::create_dir_all(config::get_state_dir())?;
+    fs::create_dir_all(config::get_runtime_dir())?;
+    let stdout = File::create(config::get_log_file_path())?;
+    let stderr = File::create(config::get_error_log_file_path())?;
+    Ok((stdout, stderr))
+}
+
+fn create_daemon() {
+    let (stdout, stderr) = match open_logs() {
+        Ok(logs) => logs,
+        Err(e) => {
+            println!("Error Starting Playback: {e}");
+            return;
+        }
+    };
+
+    let daemonize = Daemonize::new()
+        .pid_file(config::get_pid_file_path())
+        .chown_pid_file(true)
+        .working_directory("/")
+        .stdout(stdout)
+        .stderr(stderr);
+
+    match daemonize.execute() {
+        Outcome::Parent(Ok(_)) => {
+            // report what the daemon started playing once it is listening
+            for _ in 0..50 {
+                thread::sleep(POLL_INTERVAL);
+                if let Ok(Response::Status(status)) = ipc::send(&Request::Status) {
+                    status.print();
+                    return;
+                }
+            }
+            println!("Error Starting Playback");
+        }
+        Outcome::Parent(Err(_)) => println!("Error Starting Playback"),
+        Outcome::Child(Ok(_)) => {
+            run_daemon();
             process::exit(0);
-        });
-    match daemonize.start() {
-        Ok(_) => println!("Starting Playback"),
-        Err(_) => println!("Error Starting Playback"),
+        }
+        Outcome::Child(Err(_)) => process::exit(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The order a shuffled queue of ten songs is played in.
+    fn play_order(seed: u64) -> Vec<String> {
+        let mut lib = MusicLibrary::new();
+        let mut queue = Queue::new();
+        for n in 0..10 {
+            let id = format!("song{n}");
+            let song: Song = serde_json::from_value(serde_json::json!({
+                "id": id,
+                "name": id,
+                "artist": "Artist",
+                "album": "Album",
+                "duration": 60,
+                "path": format!("/music/{id}.mp3"),
+            }))
+            .unwrap();
+            lib.songs.insert(id.clone(), song);
+            queue.songs.push_back(id);
+        }
+
+        let mut settings = PlayerSettings::default();
+        settings.set_shuffle(true, Some(seed));
+        let mut rng = settings.rng();
+        let mut order = Vec::new();
+        let find = |id: &str| Ok(lib.songs.get(id).cloned());
+        while let Some(song) = queue.next_song(find, Some(&mut rng)).unwrap() {
+            queue.take(&song.id);
+            order.push(song.id);
+        }
+        order
+    }
+
+    #[test]
+    fn seeded_shuffle_repeats_its_order() {
+        let order = play_order(7);
+        assert_eq!(order.len(), 10);
+        assert_eq!(order, play_order(7));
+        assert_ne!(order, play_order(8));
+    }
+
+    #[test]
+    fn overrides_only_cover_changed_settings() {
+        let configured = PlayerSettings {
+            volume: 80,
+            repeat: RepeatMode::All,
+            ..PlayerSettings::default()
+        };
+        let mut changed = configured.clone();
+        changed.change_volume(VolumeChange::Up(10));
+        let mut overrides = SettingOverrides::default();
+        overrides.record(&configured, &changed);
+
+        // a later config edit still applies to the settings left alone
+        let mut settings = PlayerSettings {
+            volume: 50,
+            repeat: RepeatMode::One,
+            ..PlayerSettings::default()
+        };
+        overrides.apply(&mut settings);
+        assert_eq!(settings.volume, 90);
+        assert_eq!(settings.repeat, RepeatMode::One);
+    }
+
+    #[test]
+    fn replaygain_does_not_clip() {
+        let mut song: Song = serde_json::from_value(serde_json::json!({
+            "id": "song",
+            "name": "Song",
+            "artist": "Artist",
+            "album": "Album",
+            "duration": 60,
+            "path": "/music/song.mp3",
+            "track_gain": 8.0,
+        }))
+        .unwrap();
+        let settings = PlayerSettings {
+            replaygain: ReplayGainMode::Track,
+            ..PlayerSettings::default()
+        };
+
+        // without a peak a boost is dropped
+        assert_eq!(settings.song_gain(&song), 1.0);
+        // with one it is limited to full scale
+        song.track_peak = Some(0.5);
+        assert_eq!(settings.song_gain(&song), 2.0);
+        song.track_peak = Some(0.1);
+        assert!((settings.song_gain(&song) - 10f32.powf(0.4)).abs() < 0.001);
+        // cuts are never limited
+        song.track_gain = Some(-6.0);
+        song.track_peak = None;
+        assert!((settings.song_gain(&song) - 10f32.powf(-0.3)).abs() < 0.001);
     }
 }
@@ -0,0 +1,90 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the progress line is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default)]
+struct Counts {
+    found: AtomicUsize,
+    processed: AtomicUsize,
+    skipped: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl Counts {
+    fn describe(&self) -> String {
+        format!(
+            "Found {} files, processed {} ({} skipped, {} failed)",
+            self.found.load(Ordering::Relaxed),
+            self.processed.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// A progress line on stderr, redrawn from a background thread while work
+/// is counted from any thread. Nothing is drawn unless stderr is a terminal,
+/// so piped output stays clean.
+pub struct Progress {
+    counts: Arc<Counts>,
+    done: Arc<AtomicBool>,
+    reporter: Option<JoinHandle<()>>,
+}
+
+impl Progress {
+    pub fn start() -> Self {
+        let counts = Arc::new(Counts::default());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let reporter = io::stderr().is_terminal().then(|| {
+            let counts = Arc::clone(&counts);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    eprint!("\r\x1b[2K{}", counts.describe());
+                    let _ = io::stderr().flush();
+                    thread::sleep(REDRAW_INTERVAL);
+                }
+                eprint!("\r\x1b[2K");
+            })
+        });
+
+        Progress {
+            counts,
+            done,
+            reporter,
+        }
+    }
+
+    pub fn found(&self) {
+        self.counts.found.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a processed file, which may have been skipped or have failed.
+    pub fn processed(&self, skipped: bool, failed: bool) {
+        if skipped {
+            self.counts.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        if failed {
+            self.counts.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.counts.processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stops redrawing and clears the progress line.
+    pub fn finish(self) {}
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(reporter) = self.reporter.take() {
+            let _ = reporter.join();
+        }
+    }
+}
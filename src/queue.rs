@@ -1,63 +1,146 @@
-use crate::config;
-use crate::music_library::{Playlist, Song};
+use crate::lock::StateLock;
+use crate::music_library::{deserialize_song_ids, MusicLibrary, Playlist, Song};
+use crate::storage;
+use fastrand::Rng;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::collections::VecDeque;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader};
+use std::io;
+
+/// How many played songs are remembered for `previous`
+const HISTORY_LIMIT: usize = 1000;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Queue {
-    songs: VecDeque<Song>,
+    /// IDs of queued songs, resolved against the library when read
+    #[serde(deserialize_with = "deserialize_song_ids")]
+    pub songs: VecDeque<String>,
+    /// IDs of played songs, oldest first. While the player is running the
+    /// last entry is the current song.
+    #[serde(default)]
+    pub history: Vec<String>,
 }
 
 impl Queue {
     pub fn new() -> Self {
         Self {
             songs: VecDeque::new(),
+            history: Vec::new(),
         }
     }
 
     pub fn save(&self) -> io::Result<()> {
-        let file_name = config::get_queue_file_path();
-        if let Some(parent) = file_name.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-
-        let file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(file_name)?;
-        serde_json::to_writer_pretty(file, self)?;
-        Ok(())
+        storage::open()?.save_queue(self)
     }
 
     pub fn load() -> io::Result<Self> {
-        let file_name = config::get_queue_file_path();
-        let file = File::open(file_name)?;
-        let reader = BufReader::new(file);
-        let queue: Queue = serde_json::from_reader(reader)?;
-        Ok(queue)
+        storage::open()?.load_queue()
+    }
+
+    /// Loads the queue, applies `f` and saves it while holding the state
+    /// lock, so concurrent changes from the CLI and the daemon aren't lost.
+    pub fn update<T>(f: impl FnOnce(&mut Queue) -> T) -> io::Result<T> {
+        let _lock = StateLock::acquire()?;
+        let mut queue = match Queue::load() {
+            Ok(queue) => queue,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Queue::new(),
+            Err(e) => return Err(e),
+        };
+        let result = f(&mut queue);
+        queue.save()?;
+        Ok(result)
     }
 
-    pub fn add_song(&mut self, song: Song) {
-        self.songs.push_back(song);
+    pub fn add_song(&mut self, song: &Song) {
+        self.songs.push_back(song.id.clone());
     }
 
-    pub fn add_playlist(&mut self, playlist: Playlist) {
-        self.songs.extend(playlist.songs.into_iter());
+    pub fn add_playlist(&mut self, playlist: &Playlist) {
+        self.songs.extend(playlist.songs.iter().cloned());
     }
 
-    pub fn pop(&mut self) -> Option<Song> {
-        self.songs.pop_front()
+    pub fn add_songs(&mut self, songs: &[&Song]) {
+        self.songs.extend(songs.iter().map(|song| song.id.clone()));
     }
 
-    pub fn peek(&self) -> Option<&Song> {
+    pub fn peek(&self) -> Option<&String> {
         self.songs.front()
     }
 
-    pub fn remove_song(&mut self, index: usize) -> Result<Song, String> {
+    /// Picks the next song that `find` still knows of without taking it off
+    /// the queue, starting from the front or, given an `rng`, at random.
+    /// Entries pointing at removed songs are dropped.
+    pub fn next_song(
+        &mut self,
+        mut find: impl FnMut(&str) -> io::Result<Option<Song>>,
+        mut rng: Option<&mut Rng>,
+    ) -> io::Result<Option<Song>> {
+        while !self.songs.is_empty() {
+            let index = match rng.as_mut() {
+                Some(rng) => rng.usize(..self.songs.len()),
+                None => 0,
+            };
+            match find(&self.songs[index])? {
+                Some(song) => return Ok(Some(song)),
+                None => {
+                    if let Some(id) = self.songs.remove(index) {
+                        eprintln!("Skipping missing song '{id}'");
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Takes the first entry for the song off the queue, returning whether
+    /// there was one.
+    pub fn take(&mut self, id: &str) -> bool {
+        match self.songs.iter().position(|song_id| song_id == id) {
+            Some(index) => self.songs.remove(index).is_some(),
+            None => false,
+        }
+    }
+
+    /// Randomly reorders the queue.
+    pub fn shuffle(&mut self, rng: &mut Rng) {
+        rng.shuffle(self.songs.make_contiguous());
+    }
+
+    /// Records that a song started playing.
+    pub fn record(&mut self, id: &str) {
+        self.history.push(id.to_string());
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.drain(..self.history.len() - HISTORY_LIMIT);
+        }
+    }
+
+    /// Moves the current song and the one played before it from the history
+    /// back to the front of the queue. Returns `false` when there is no
+    /// previous song. With `repeat_all` the previous song went back to the
+    /// end of the queue when it finished, and that copy is dropped since it
+    /// is about to be played again.
+    pub fn rewind(&mut self, repeat_all: bool) -> bool {
+        if self.history.len() < 2 {
+            return false;
+        }
+        let previous = &self.history[self.history.len() - 2];
+        if repeat_all && self.songs.back() == Some(previous) {
+            self.songs.pop_back();
+        }
+        for id in self.history.drain(self.history.len() - 2..).rev() {
+            self.songs.push_front(id);
+        }
+        true
+    }
+
+    /// Removes every occurrence of the song from the queue, returning how
+    /// many entries were removed.
+    pub fn remove_id(&mut self, id: &str) -> usize {
+        let before = self.songs.len();
+        self.songs.retain(|song_id| song_id != id);
+        before - self.songs.len()
+    }
+
+    pub fn remove_song(&mut self, index: usize) -> Result<String, String> {
         if let Some(song) = self.songs.remove(index) {
             Ok(song)
         } else {
@@ -73,13 +156,56 @@ impl Queue {
         self.songs.clear();
     }
 
-    pub fn list(&self) {
+    pub fn list(&self, lib: &MusicLibrary) {
         if self.songs.is_empty() {
             println!("The Queue is empty!");
         } else {
-            for (i, song) in self.songs.iter().enumerate() {
-                println!("{} - {} by {}", i, song.name.clone(), song.artist.clone());
+            for (i, id) in self.songs.iter().enumerate() {
+                match lib.songs.get(id) {
+                    Some(song) => println!("{} - {} by {}", i, song.name, song.artist),
+                    None => println!("{i} - <missing song '{id}'>"),
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(songs: &[&str], history: &[&str]) -> Queue {
+        let mut queue = Queue::new();
+        queue.songs = songs.iter().map(|id| id.to_string()).collect();
+        for id in history {
+            queue.record(id);
+        }
+        queue
+    }
+
+    #[test]
+    fn rewind_needs_a_previous_song() {
+        let mut empty = queue(&["b"], &[]);
+        assert!(!empty.rewind(false));
+        let mut first = queue(&["b"], &["a"]);
+        assert!(!first.rewind(false));
+        assert_eq!(first.songs, ["b"]);
+        assert_eq!(first.history, ["a"]);
+    }
+
+    #[test]
+    fn rewind_requeues_the_previous_and_current_songs() {
+        let mut queue = queue(&["c", "a"], &["a", "b"]);
+        assert!(queue.rewind(false));
+        assert_eq!(queue.songs, ["a", "b", "c", "a"]);
+        assert!(queue.history.is_empty());
+    }
+
+    #[test]
+    fn rewind_drops_the_repeated_copy_with_repeat_all() {
+        // "a" went back to the end of the queue when it finished
+        let mut queue = queue(&["c", "a"], &["a", "b"]);
+        assert!(queue.rewind(true));
+        assert_eq!(queue.songs, ["a", "b", "c"]);
+    }
+}
@@ -0,0 +1,92 @@
+use super::Storage;
+use crate::config;
+use crate::music_library::{song_id, MusicLibrary, Song};
+use crate::persist;
+use crate::queue::Queue;
+use std::io;
+use std::path::Path;
+
+/// Stores the library and queue as pretty-printed JSON files.
+pub struct JsonStorage;
+
+impl Storage for JsonStorage {
+    fn load_library(&self) -> io::Result<MusicLibrary> {
+        read_library(&config::get_library_file_path())
+    }
+
+    fn load_song(&self, id: &str) -> io::Result<Option<Song>> {
+        Ok(self.load_library()?.songs.remove(id))
+    }
+
+    fn save_library(&self, library: &MusicLibrary) -> io::Result<()> {
+        persist::write_json(&config::get_library_file_path(), library)
+    }
+
+    fn load_queue(&self) -> io::Result<Queue> {
+        read_queue(&config::get_queue_file_path())
+    }
+
+    fn save_queue(&self, queue: &Queue) -> io::Result<()> {
+        persist::write_json(&config::get_queue_file_path(), queue)
+    }
+}
+
+pub fn read_library(path: &Path) -> io::Result<MusicLibrary> {
+    let mut library: MusicLibrary = persist::read_json(path)?;
+
+    // libraries written before songs had IDs are keyed by name
+    if library.songs.values().any(|song| song.id.is_empty()) {
+        library.songs = library
+            .songs
+            .into_values()
+            .map(|mut song| {
+                if song.id.is_empty() {
+                    song.id = song_id(Path::new(&song.path));
+                }
+                (song.id.clone(), song)
+            })
+            .collect();
+    }
+    Ok(library)
+}
+
+pub fn read_queue(path: &Path) -> io::Result<Queue> {
+    persist::read_json(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const BASELINE_LIBRARY: &str = include_str!("../../tests/fixtures/baseline/music_library.json");
+    const BASELINE_QUEUE: &str = include_str!("../../tests/fixtures/baseline/queue.json");
+
+    #[test]
+    fn reads_baseline_library_and_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let library_file = dir.path().join("music_library.json");
+        let queue_file = dir.path().join("queue.json");
+        fs::write(&library_file, BASELINE_LIBRARY).unwrap();
+        fs::write(&queue_file, BASELINE_QUEUE).unwrap();
+
+        let intro = song_id(Path::new("/music/a/intro.mp3"));
+        let outro = song_id(Path::new("/music/a/outro.mp3"));
+
+        let library = read_library(&library_file).unwrap();
+        assert_eq!(library.songs.len(), 2);
+        assert_eq!(library.songs[&intro].name, "Intro");
+        assert_eq!(library.songs[&outro].id, outro);
+        assert_eq!(
+            library.playlists["Mix"].songs,
+            vec![outro.clone(), intro.clone()]
+        );
+
+        let queue = read_queue(&queue_file).unwrap();
+        assert_eq!(queue.songs, [intro]);
+        assert!(queue.history.is_empty());
+
+        // nothing was mistaken for corruption
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,38 @@
+mod json;
+mod sqlite;
+
+use crate::music_library::{MusicLibrary, Song};
+use crate::queue::Queue;
+use std::env;
+use std::io;
+
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+
+/// Environment variable selecting the storage backend (`sqlite` or `json`).
+const BACKEND_VAR: &str = "MUSIC_LIB_STORAGE";
+
+/// Persistence for the library and queue.
+pub trait Storage {
+    fn load_library(&self) -> io::Result<MusicLibrary>;
+    /// Loads a single song, without reading the rest of the library where
+    /// the backend allows it.
+    fn load_song(&self, id: &str) -> io::Result<Option<Song>>;
+    /// Persists the library. Backends may write only the songs and
+    /// playlists recorded in `MusicLibrary::changes`.
+    fn save_library(&self, library: &MusicLibrary) -> io::Result<()>;
+    fn load_queue(&self) -> io::Result<Queue>;
+    fn save_queue(&self, queue: &Queue) -> io::Result<()>;
+}
+
+/// Opens the configured storage backend, defaulting to SQLite.
+pub fn open() -> io::Result<Box<dyn Storage>> {
+    match env::var(BACKEND_VAR).as_deref() {
+        Ok("json") => Ok(Box::new(JsonStorage)),
+        Ok("sqlite") | Err(_) => Ok(Box::new(SqliteStorage::open()?)),
+        Ok(other) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown storage backend '{other}'"),
+        )),
+    }
+}
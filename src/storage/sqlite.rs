@@ -0,0 +1,535 @@
+use super::json;
+use super::Storage;
+use crate::config;
+use crate::music_library::{MusicLibrary, Playlist, Song};
+use crate::queue::Queue;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Schema migrations, applied in order. `PRAGMA user_version` records how
+/// many of them have already run.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE songs (
+        id       TEXT PRIMARY KEY,
+        name     TEXT NOT NULL,
+        artist   TEXT NOT NULL,
+        album    TEXT NOT NULL,
+        duration INTEGER NOT NULL,
+        path     TEXT NOT NULL
+    );
+    CREATE INDEX songs_name ON songs (name);
+    CREATE INDEX songs_artist ON songs (artist);
+    CREATE INDEX songs_album ON songs (album);
+    CREATE INDEX songs_path ON songs (path);
+
+    CREATE TABLE playlists (
+        name TEXT PRIMARY KEY
+    );
+
+    CREATE TABLE playlist_entries (
+        playlist TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        song_id  TEXT NOT NULL,
+        PRIMARY KEY (playlist, position)
+    );
+    CREATE INDEX playlist_entries_song ON playlist_entries (song_id);
+
+    CREATE TABLE queue (
+        position INTEGER PRIMARY KEY,
+        song_id  TEXT NOT NULL
+    );
+    CREATE INDEX queue_song ON queue (song_id);
+",
+    "
+    CREATE TABLE history (
+        position INTEGER PRIMARY KEY,
+        song_id  TEXT NOT NULL
+    );
+",
+    "
+    ALTER TABLE songs ADD COLUMN track_gain REAL;
+    ALTER TABLE songs ADD COLUMN album_gain REAL;
+",
+    "
+    ALTER TABLE songs ADD COLUMN track INTEGER;
+    ALTER TABLE songs ADD COLUMN disc INTEGER;
+    ALTER TABLE songs ADD COLUMN year INTEGER;
+    ALTER TABLE songs ADD COLUMN genre TEXT;
+    ALTER TABLE songs ADD COLUMN album_artist TEXT;
+    ALTER TABLE songs ADD COLUMN composer TEXT;
+    ALTER TABLE songs ADD COLUMN bitrate INTEGER;
+    ALTER TABLE songs ADD COLUMN sample_rate INTEGER;
+    ALTER TABLE songs ADD COLUMN channels INTEGER;
+    CREATE INDEX songs_album_artist ON songs (album_artist);
+",
+    "
+    ALTER TABLE songs ADD COLUMN modified INTEGER;
+    ALTER TABLE songs ADD COLUMN size INTEGER;
+",
+    "
+    ALTER TABLE songs ADD COLUMN hash TEXT;
+    CREATE INDEX songs_hash ON songs (hash);
+",
+    "
+    ALTER TABLE songs ADD COLUMN track_peak REAL;
+    ALTER TABLE songs ADD COLUMN album_peak REAL;
+",
+];
+
+/// Stores the library and queue in an embedded SQLite database.
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    /// Opens the database, creating or upgrading its schema and importing
+    /// any JSON files left behind by the JSON backend.
+    pub fn open() -> io::Result<Self> {
+        let storage = SqliteStorage::open_at(&config::get_database_file_path())?;
+        storage.import_json(
+            &config::get_library_file_path(),
+            &config::get_queue_file_path(),
+        )?;
+        Ok(storage)
+    }
+
+    /// Opens the database at `path`, creating or upgrading its schema.
+    pub fn open_at(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path).map_err(io::Error::other)?;
+        let storage = SqliteStorage { conn };
+        storage.init().map_err(io::Error::other)?;
+        Ok(storage)
+    }
+
+    fn init(&self) -> rusqlite::Result<()> {
+        // the player daemon and CLI share the database
+        self.conn.busy_timeout(Duration::from_secs(5))?;
+        self.conn
+            .pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+
+        let version: usize = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.unchecked_transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /// One-time migration from `music_library.json` and `queue.json`. The
+    /// imported files are renamed so they are not imported again. A file
+    /// that cannot be parsed is reported and renamed aside to
+    /// `*.json.import-failed` instead, so it doesn't stop the database from
+    /// opening.
+    pub fn import_json(&self, library_file: &Path, queue_file: &Path) -> io::Result<()> {
+        let library = read_json_file(library_file, json::read_library);
+        let queue = read_json_file(queue_file, json::read_queue);
+        if library.is_none() && queue.is_none() {
+            return Ok(());
+        }
+
+        let import = || -> rusqlite::Result<()> {
+            let tx = self.conn.unchecked_transaction()?;
+            if let Some(library) = &library {
+                for song in library.songs.values() {
+                    write_song(&tx, &song.id, Some(song))?;
+                }
+                for playlist in library.playlists.values() {
+                    write_playlist(&tx, &playlist.name, Some(playlist))?;
+                }
+            }
+            if let Some(queue) = &queue {
+                write_queue(&tx, queue)?;
+            }
+            tx.commit()
+        };
+        import().map_err(io::Error::other)?;
+
+        for (file, imported) in [
+            (library_file, library.is_some()),
+            (queue_file, queue.is_some()),
+        ] {
+            if imported {
+                fs::rename(file, file.with_extension("json.migrated"))?;
+            }
+        }
+        println!(
+            "Migrated JSON library to {}",
+            self.conn.path().unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    fn read_library(&self) -> rusqlite::Result<MusicLibrary> {
+        let mut library = MusicLibrary::new();
+
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {SONG_COLUMNS} FROM songs"))?;
+        let songs = stmt.query_map([], read_song)?;
+        for song in songs {
+            let song = song?;
+            library.songs.insert(song.id.clone(), song);
+        }
+
+        let mut stmt = self.conn.prepare("SELECT name FROM playlists")?;
+        let names = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        for name in names {
+            let name = name?;
+            library.playlists.insert(
+                name.clone(),
+                Playlist {
+                    name,
+                    songs: Vec::new(),
+                },
+            );
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT playlist, song_id FROM playlist_entries ORDER BY playlist, position",
+        )?;
+        let entries = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for entry in entries {
+            let (name, song_id) = entry?;
+            if let Some(playlist) = library.playlists.get_mut(&name) {
+                playlist.songs.push(song_id);
+            }
+        }
+
+        Ok(library)
+    }
+
+    fn write_library(&self, library: &MusicLibrary) -> rusqlite::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for id in &library.changes.songs {
+            write_song(&tx, id, library.songs.get(id))?;
+        }
+        for name in &library.changes.playlists {
+            write_playlist(&tx, name, library.playlists.get(name))?;
+        }
+        tx.commit()
+    }
+
+    fn read_queue(&self) -> rusqlite::Result<Queue> {
+        let mut queue = Queue::new();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT song_id FROM queue ORDER BY position")?;
+        for id in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            queue.songs.push_back(id?);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT song_id FROM history ORDER BY position")?;
+        for id in stmt.query_map([], |row| row.get::<_, String>(0))? {
+            queue.history.push(id?);
+        }
+        Ok(queue)
+    }
+
+    fn write_queue(&self, queue: &Queue) -> rusqlite::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        write_queue(&tx, queue)?;
+        tx.commit()
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_library(&self) -> io::Result<MusicLibrary> {
+        self.read_library().map_err(io::Error::other)
+    }
+
+    fn load_song(&self, id: &str) -> io::Result<Option<Song>> {
+        self.conn
+            .query_row(
+                &format!("SELECT {SONG_COLUMNS} FROM songs WHERE id = ?1"),
+                [id],
+                read_song,
+            )
+            .optional()
+            .map_err(io::Error::other)
+    }
+
+    fn save_library(&self, library: &MusicLibrary) -> io::Result<()> {
+        self.write_library(library).map_err(io::Error::other)
+    }
+
+    fn load_queue(&self) -> io::Result<Queue> {
+        self.read_queue().map_err(io::Error::other)
+    }
+
+    fn save_queue(&self, queue: &Queue) -> io::Result<()> {
+        self.write_queue(queue).map_err(io::Error::other)
+    }
+}
+
+/// Reads a JSON file left for import, if there is one. One that cannot be
+/// parsed is renamed aside so the import isn't attempted again.
+fn read_json_file<T>(path: &Path, read: fn(&Path) -> io::Result<T>) -> Option<T> {
+    if !path.exists() {
+        return None;
+    }
+    match read(path) {
+        Ok(value) => Some(value),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            eprintln!("{e}");
+            let failed = path.with_extension("json.import-failed");
+            match fs::rename(path, &failed) {
+                Ok(()) => eprintln!("It was not imported and was moved to {}", failed.display()),
+                Err(e) => eprintln!("It was not imported and could not be moved aside: {e}"),
+            }
+            None
+        }
+        Err(e) => {
+            eprintln!("Unable to import {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Upserts the song with the given ID, or deletes it when `song` is `None`.
+/// The columns `read_song` expects, in order.
+const SONG_COLUMNS: &str = "id, name, artist, album, duration, path, track_gain, album_gain, track,
+    disc, year, genre, album_artist, composer, bitrate, sample_rate, channels, modified, size,
+    hash, track_peak, album_peak";
+
+fn read_song(row: &Row) -> rusqlite::Result<Song> {
+    Ok(Song {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        artist: row.get(2)?,
+        album: row.get(3)?,
+        duration: row.get(4)?,
+        path: row.get(5)?,
+        track_gain: row.get(6)?,
+        album_gain: row.get(7)?,
+        track: row.get(8)?,
+        disc: row.get(9)?,
+        year: row.get(10)?,
+        genre: row.get(11)?,
+        album_artist: row.get(12)?,
+        composer: row.get(13)?,
+        bitrate: row.get(14)?,
+        sample_rate: row.get(15)?,
+        channels: row.get(16)?,
+        modified: row.get(17)?,
+        size: row.get(18)?,
+        hash: row.get(19)?,
+        track_peak: row.get(20)?,
+        album_peak: row.get(21)?,
+    })
+}
+
+fn write_song(conn: &Connection, id: &str, song: Option<&Song>) -> rusqlite::Result<()> {
+    match song {
+        Some(song) => conn.execute(
+            "INSERT OR REPLACE INTO songs
+                 (id, name, artist, album, duration, path, track_gain, album_gain, track, disc,
+                  year, genre, album_artist, composer, bitrate, sample_rate, channels, modified,
+                  size, hash, track_peak, album_peak)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                     ?18, ?19, ?20, ?21, ?22)",
+            params![
+                song.id,
+                song.name,
+                song.artist,
+                song.album,
+                song.duration,
+                song.path,
+                song.track_gain,
+                song.album_gain,
+                song.track,
+                song.disc,
+                song.year,
+                song.genre,
+                song.album_artist,
+                song.composer,
+                song.bitrate,
+                song.sample_rate,
+                song.channels,
+                song.modified,
+                song.size,
+                song.hash,
+                song.track_peak,
+                song.album_peak
+            ],
+        )?,
+        None => conn.execute("DELETE FROM songs WHERE id = ?1", [id])?,
+    };
+    Ok(())
+}
+
+/// Replaces the named playlist and its entries, or deletes it when
+/// `playlist` is `None`.
+fn write_playlist(
+    conn: &Connection,
+    name: &str,
+    playlist: Option<&Playlist>,
+) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM playlist_entries WHERE playlist = ?1", [name])?;
+    conn.execute("DELETE FROM playlists WHERE name = ?1", [name])?;
+
+    if let Some(playlist) = playlist {
+        conn.execute("INSERT INTO playlists (name) VALUES (?1)", [name])?;
+        let mut stmt = conn.prepare(
+            "INSERT INTO playlist_entries (playlist, position, song_id) VALUES (?1, ?2, ?3)",
+        )?;
+        for (position, song_id) in playlist.songs.iter().enumerate() {
+            stmt.execute(params![name, position, song_id])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_queue(conn: &Connection, queue: &Queue) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM queue", [])?;
+    let mut stmt = conn.prepare("INSERT INTO queue (position, song_id) VALUES (?1, ?2)")?;
+    for (position, song_id) in queue.songs.iter().enumerate() {
+        stmt.execute(params![position, song_id])?;
+    }
+
+    conn.execute("DELETE FROM history", [])?;
+    let mut stmt = conn.prepare("INSERT INTO history (position, song_id) VALUES (?1, ?2)")?;
+    for (position, song_id) in queue.history.iter().enumerate() {
+        stmt.execute(params![position, song_id])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_library::song_id;
+
+    const BASELINE_LIBRARY: &str = include_str!("../../tests/fixtures/baseline/music_library.json");
+    const BASELINE_QUEUE: &str = include_str!("../../tests/fixtures/baseline/queue.json");
+
+    /// The JSON files, imported or not, left in `dir`
+    fn json_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.contains(".json"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn imports_baseline_json_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let library_file = dir.path().join("music_library.json");
+        let queue_file = dir.path().join("queue.json");
+        fs::write(&library_file, BASELINE_LIBRARY).unwrap();
+        fs::write(&queue_file, BASELINE_QUEUE).unwrap();
+
+        let storage = SqliteStorage::open_at(&dir.path().join("music_library.db")).unwrap();
+        storage.import_json(&library_file, &queue_file).unwrap();
+        storage.import_json(&library_file, &queue_file).unwrap();
+
+        let intro = song_id(Path::new("/music/a/intro.mp3"));
+        let library = storage.load_library().unwrap();
+        assert_eq!(library.songs.len(), 2);
+        assert_eq!(library.songs[&intro].name, "Intro");
+        assert_eq!(library.playlists["Mix"].songs.len(), 2);
+        assert_eq!(
+            storage.load_song(&intro).unwrap(),
+            Some(library.songs[&intro].clone())
+        );
+        assert_eq!(storage.load_song("missing").unwrap(), None);
+        assert_eq!(storage.load_queue().unwrap().songs, [intro]);
+        assert_eq!(
+            json_files(dir.path()),
+            ["music_library.json.migrated", "queue.json.migrated"]
+        );
+    }
+
+    #[test]
+    fn corrupt_json_is_set_aside_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let library_file = dir.path().join("music_library.json");
+        let queue_file = dir.path().join("queue.json");
+        fs::write(&library_file, "{ not json").unwrap();
+        fs::write(&queue_file, BASELINE_QUEUE).unwrap();
+
+        let db = dir.path().join("music_library.db");
+        for _ in 0..2 {
+            let storage = SqliteStorage::open_at(&db).unwrap();
+            storage.import_json(&library_file, &queue_file).unwrap();
+        }
+
+        let storage = SqliteStorage::open_at(&db).unwrap();
+        assert!(storage.load_library().unwrap().songs.is_empty());
+        assert_eq!(storage.load_queue().unwrap().songs.len(), 1);
+
+        let files = json_files(dir.path());
+        assert_eq!(files.len(), 3, "{files:?}");
+        assert!(files[0].starts_with("music_library.json.corrupt-"));
+        assert_eq!(
+            files[1..],
+            ["music_library.json.import-failed", "queue.json.migrated"]
+        );
+    }
+
+    #[test]
+    fn upgrades_a_first_version_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("music_library.db");
+        let conn = Connection::open(&db).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute_batch(
+            "
+            INSERT INTO songs VALUES ('a1', 'Intro', 'Band', 'Debut', 63, '/music/a/intro.mp3');
+            INSERT INTO playlists VALUES ('Mix');
+            INSERT INTO playlist_entries VALUES ('Mix', 0, 'a1');
+            INSERT INTO queue VALUES (0, 'a1');
+            ",
+        )
+        .unwrap();
+        drop(conn);
+
+        let storage = SqliteStorage::open_at(&db).unwrap();
+        let version: usize = storage
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        let mut library = storage.load_library().unwrap();
+        let song = library.songs.get_mut("a1").unwrap();
+        assert_eq!((song.name.as_str(), song.duration), ("Intro", 63));
+        assert_eq!(
+            (song.track_gain, song.year, song.hash.as_deref()),
+            (None, None, None)
+        );
+        assert_eq!(library.playlists["Mix"].songs, ["a1"]);
+        assert_eq!(storage.load_queue().unwrap().songs, ["a1"]);
+
+        // columns added by later migrations can be written
+        song.year = Some(1999);
+        song.hash = Some("abc".to_string());
+        library.changes.songs.insert("a1".to_string());
+        storage.save_library(&library).unwrap();
+        drop(storage);
+
+        // reopening leaves an up to date database alone
+        let storage = SqliteStorage::open_at(&db).unwrap();
+        let song = &storage.load_library().unwrap().songs["a1"];
+        assert_eq!((song.year, song.hash.as_deref()), (Some(1999), Some("abc")));
+    }
+}
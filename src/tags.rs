@@ -0,0 +1,254 @@
+use crate::music_library::{EditSong, Song};
+use lofty::config::WriteOptions;
+use lofty::file::TaggedFileExt;
+use lofty::probe::Probe;
+use lofty::tag::{Accessor, ItemKey, Tag, TagExt};
+use rodio::Decoder;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::process;
+
+/// Song fields stored in file tags
+pub const TAG_FIELDS: [EditSong; 9] = [
+    EditSong::Name,
+    EditSong::Artist,
+    EditSong::Album,
+    EditSong::Track,
+    EditSong::Disc,
+    EditSong::Year,
+    EditSong::Genre,
+    EditSong::AlbumArtist,
+    EditSong::Composer,
+];
+
+/// A field going from one value to another, `None` meaning unset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub field: EditSong,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<String>| match value {
+            Some(value) => format!("\"{value}\""),
+            None => "(none)".to_string(),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.field,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
+/// The song's value for a field, as shown and written to tags.
+pub fn field_value(song: &Song, field: EditSong) -> Option<String> {
+    match field {
+        EditSong::Name => Some(song.name.clone()),
+        EditSong::Artist => Some(song.artist.clone()),
+        EditSong::Album => Some(song.album.clone()),
+        EditSong::Path => Some(song.path.clone()),
+        EditSong::Track => song.track.map(|track| track.to_string()),
+        EditSong::Disc => song.disc.map(|disc| disc.to_string()),
+        EditSong::Year => song.year.map(|year| year.to_string()),
+        EditSong::Genre => song.genre.clone(),
+        EditSong::AlbumArtist => song.album_artist.clone(),
+        EditSong::Composer => song.composer.clone(),
+    }
+}
+
+/// Fields that differ between two versions of a song.
+pub fn changes(old: &Song, new: &Song) -> Vec<Change> {
+    [EditSong::Path]
+        .into_iter()
+        .chain(TAG_FIELDS)
+        .filter_map(|field| {
+            let (old, new) = (field_value(old, field), field_value(new, field));
+            (old != new).then_some(Change { field, old, new })
+        })
+        .collect()
+}
+
+/// How the tags in the song's file differ from the song.
+pub fn diff(song: &Song) -> io::Result<Vec<Change>> {
+    let path = Path::new(&song.path);
+    let tagged_file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(io::Error::other)?;
+    let tag = tagged_file
+        .primary_tag()
+        .or_else(|| tagged_file.first_tag());
+    Ok(tag_changes(song, path, tag))
+}
+
+/// Brings the tags in the song's file in line with the song, returning what
+/// changed. The file is edited as a copy that replaces it only once its
+/// tags read back as written and its audio decodes exactly as before.
+pub fn write(song: &Song) -> io::Result<Vec<Change>> {
+    let changes = diff(song)?;
+    if changes.is_empty() {
+        return Ok(changes);
+    }
+
+    let path = Path::new(&song.path);
+    let parent = path.parent().unwrap_or(Path::new("."));
+    // keeps the extension, which the format is detected from
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let tmp_path = parent.join(format!(".{stem}.tmp-{}.{extension}", process::id()));
+
+    let result = (|| {
+        fs::copy(path, &tmp_path)?;
+        write_changes(&tmp_path, &changes)?;
+        verify(song, path, &tmp_path)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result.map(|_| changes)
+}
+
+fn write_changes(path: &Path, changes: &[Change]) -> io::Result<()> {
+    let mut tagged_file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(io::Error::other)?;
+    if tagged_file.primary_tag().is_none() {
+        // start from the tag the values were read from, so fields that
+        // aren't changing are carried over
+        let mut tag = Tag::new(tagged_file.primary_tag_type());
+        if let Some(existing) = tagged_file.first_tag() {
+            for item in existing.items() {
+                tag.insert(item.clone());
+            }
+            for picture in existing.pictures() {
+                tag.push_picture(picture.clone());
+            }
+        }
+        tagged_file.insert_tag(tag);
+    }
+    let Some(tag) = tagged_file.primary_tag_mut() else {
+        return Err(io::Error::other("unable to add a tag"));
+    };
+    for change in changes {
+        set_tag(tag, change.field, change.new.clone());
+    }
+    tag.save_to_path(path, WriteOptions::default())
+        .map_err(io::Error::other)
+}
+
+/// Checks that the edited copy holds the song's tags and the original audio.
+fn verify(song: &Song, original: &Path, copy: &Path) -> io::Result<()> {
+    let tagged_file = Probe::open(copy)
+        .and_then(|probe| probe.read())
+        .map_err(io::Error::other)?;
+    let tag = tagged_file
+        .primary_tag()
+        .or_else(|| tagged_file.first_tag());
+    if let Some(change) = tag_changes(song, original, tag).first() {
+        return Err(io::Error::other(format!(
+            "the file cannot hold the change to {}",
+            change.field
+        )));
+    }
+
+    let decode = |path: &Path| {
+        let file = File::open(path)?;
+        Decoder::new(BufReader::new(file)).map_err(io::Error::other)
+    };
+    if !decode(original)?.eq(decode(copy)?) {
+        return Err(io::Error::other("writing tags would change the audio"));
+    }
+    Ok(())
+}
+
+fn tag_changes(song: &Song, path: &Path, tag: Option<&Tag>) -> Vec<Change> {
+    TAG_FIELDS
+        .into_iter()
+        .filter_map(|field| {
+            let old = read_tag(tag, field);
+            let new = desired_value(song, path, field, &old);
+            (old != new).then_some(Change { field, old, new })
+        })
+        .collect()
+}
+
+/// The value the tag should have. Placeholders filled in when the file was
+/// read, such as an "Unknown" artist, aren't written back.
+fn desired_value(
+    song: &Song,
+    path: &Path,
+    field: EditSong,
+    current: &Option<String>,
+) -> Option<String> {
+    let value = field_value(song, field);
+    let placeholder = match field {
+        EditSong::Name => path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned()),
+        EditSong::Artist | EditSong::Album => Some("Unknown".to_string()),
+        _ => None,
+    };
+    if current.is_none() && value.is_some() && value == placeholder {
+        None
+    } else {
+        value
+    }
+}
+
+fn read_tag(tag: Option<&Tag>, field: EditSong) -> Option<String> {
+    let tag = tag?;
+    match field {
+        EditSong::Name => tag.get_string(&ItemKey::TrackTitle).map(str::to_string),
+        EditSong::Artist => tag.get_string(&ItemKey::TrackArtist).map(str::to_string),
+        EditSong::Album => tag.get_string(&ItemKey::AlbumTitle).map(str::to_string),
+        EditSong::Path => None,
+        EditSong::Track => tag.track().map(|track| track.to_string()),
+        EditSong::Disc => tag.disk().map(|disc| disc.to_string()),
+        EditSong::Year => tag.year().map(|year| year.to_string()),
+        EditSong::Genre => tag.genre().map(|genre| genre.into_owned()),
+        EditSong::AlbumArtist => tag.get_string(&ItemKey::AlbumArtist).map(str::to_string),
+        EditSong::Composer => tag.get_string(&ItemKey::Composer).map(str::to_string),
+    }
+}
+
+fn set_tag(tag: &mut Tag, field: EditSong, value: Option<String>) {
+    let number = value.as_deref().and_then(|value| value.parse::<u32>().ok());
+    match field {
+        EditSong::Name => set_text(tag, ItemKey::TrackTitle, value),
+        EditSong::Artist => set_text(tag, ItemKey::TrackArtist, value),
+        EditSong::Album => set_text(tag, ItemKey::AlbumTitle, value),
+        EditSong::Path => {}
+        EditSong::Track => match number {
+            Some(track) => tag.set_track(track),
+            None => tag.remove_track(),
+        },
+        EditSong::Disc => match number {
+            Some(disc) => tag.set_disk(disc),
+            None => tag.remove_disk(),
+        },
+        EditSong::Year => match number {
+            Some(year) => tag.set_year(year),
+            None => tag.remove_year(),
+        },
+        EditSong::Genre => set_text(tag, ItemKey::Genre, value),
+        EditSong::AlbumArtist => set_text(tag, ItemKey::AlbumArtist, value),
+        EditSong::Composer => set_text(tag, ItemKey::Composer, value),
+    }
+}
+
+fn set_text(tag: &mut Tag, key: ItemKey, value: Option<String>) {
+    match value {
+        Some(value) => {
+            tag.insert_text(key, value);
+        }
+        None => tag.remove_key(&key),
+    }
+}
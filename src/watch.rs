@@ -0,0 +1,110 @@
+use crate::music_library::{is_music_file, MusicLibrary, ScanOptions};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Keeps the library in sync with `roots` until interrupted. Once files
+/// under a root change and then nothing changes for `delay`, that root is
+/// rescanned, so files that are still being copied aren't read too early.
+pub fn watch(roots: &[String], options: &ScanOptions, delay: Duration) -> io::Result<()> {
+    // events carry absolute paths with symlinks resolved, so roots are
+    // compared in the same form
+    let roots = roots
+        .iter()
+        .map(|root| {
+            fs::canonicalize(root).map_err(|e| io::Error::new(e.kind(), format!("{root}: {e}")))
+        })
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(io::Error::other)?;
+    for root in &roots {
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .map_err(|e| io::Error::other(format!("{}: {e}", root.display())))?;
+        println!("Watching {} for changes", root.display());
+    }
+
+    while let Some(changes) = wait_for_changes(&events, delay) {
+        // reloaded for every rescan so changes made meanwhile aren't lost
+        let mut lib = match MusicLibrary::load() {
+            Ok(lib) => lib,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => MusicLibrary::new(),
+            Err(e) => {
+                eprintln!("Error loading music library: {e}");
+                continue;
+            }
+        };
+        // scanned together so files moved between roots keep their IDs
+        let affected: Vec<String> = roots
+            .iter()
+            .filter(|root| affects(&changes, root))
+            .map(|root| root.to_string_lossy().into_owned())
+            .collect();
+        lib.scan_directories(&affected, options);
+        if let Err(e) = lib.save() {
+            println!("Error while saving data: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Blocks until a relevant change arrives and then until none has arrived
+/// for `delay`, returning the relevant events. Returns `None` once the
+/// watcher has stopped.
+fn wait_for_changes(
+    events: &Receiver<notify::Result<Event>>,
+    delay: Duration,
+) -> Option<Vec<notify::Result<Event>>> {
+    let mut changes = Vec::new();
+    while changes.is_empty() {
+        let event = events.recv().ok()?;
+        if is_relevant(&event) {
+            changes.push(event);
+        }
+    }
+
+    let mut deadline = Instant::now() + delay;
+    loop {
+        match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(event) if is_relevant(&event) => {
+                changes.push(event);
+                deadline = Instant::now() + delay;
+            }
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => return Some(changes),
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+/// Whether any of the changes is under `root`. Watcher errors affect every
+/// root.
+fn affects(changes: &[notify::Result<Event>], root: &Path) -> bool {
+    changes.iter().any(|change| match change {
+        Ok(event) => event.paths.iter().any(|path| path.starts_with(root)),
+        Err(_) => true,
+    })
+}
+
+/// Whether an event may affect the library. Reads are ignored, as scanning
+/// and playback open files themselves. Paths without an extension may be
+/// directories, so they count too. Watcher errors such as dropped events
+/// count as well, since a rescan catches up on whatever was missed.
+fn is_relevant(event: &notify::Result<Event>) -> bool {
+    match event {
+        Ok(event) => {
+            !matches!(event.kind, EventKind::Access(_))
+                && event
+                    .paths
+                    .iter()
+                    .any(|path| is_music_file(path) || path.extension().is_none())
+        }
+        Err(e) => {
+            eprintln!("Error watching for changes: {e}");
+            true
+        }
+    }
+}